@@ -24,7 +24,7 @@ use near_sdk::{
     PromiseResult,
 };
 use near_sdk::{log, Gas};
-use shared::{MetadataReference, TokenWithRatioValid};
+use shared::{MetadataReference, SetConfig, TokenWithRatioValid};
 
 setup_alloc!();
 const BASE_GAS: Gas = 5_000_000_000_000;
@@ -92,6 +92,7 @@ impl Contract {
         owner_fee: U128,
         updatable_fee: Option<bool>,
         metadata_reference: Option<MetadataReference>,
+        config: Option<SetConfig>,
     ) {
         assert_one_yocto();
         let account_id = format!("{}.{}", contract_account_prefix, env::current_account_id());
@@ -128,12 +129,14 @@ impl Contract {
                         "owner_fee": owner_fee,
                         "updatable_fee": updatable_fee,
                         "metadata_reference": metadata_reference,
+                        "config": config,
                 })
                 .to_string()
                 .as_bytes()
                 .to_vec(),
                 0,
-                BASE_GAS * 10,
+                // Leave enough gas for fetching the metadata of the underlying tokens
+                BASE_GAS * 20,
             );
 
         prom.then(
@@ -152,7 +155,7 @@ impl Contract {
                     "owner_fee": owner_fee,
                     "updatable_fee": updatable_fee,
                     "metadata_reference": metadata_reference,
-
+                    "config": config,
                 })
                 .to_string()
                 .as_bytes()
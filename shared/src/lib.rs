@@ -1,6 +1,6 @@
 use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize},
-    json_types::ValidAccountId,
+    json_types::{ValidAccountId, U128},
     serde::{self, Deserialize, Serialize},
     AccountId, PanicOnDefault,
 };
@@ -9,14 +9,22 @@ use near_sdk::{
 #[serde(crate = "near_sdk::serde")]
 pub struct TokenWithRatioValid {
     pub token_id: ValidAccountId,
-    pub ratio: u32,
+    /// The numerator of the ratio
+    pub ratio: U128,
+    /// The denominator of the ratio, defaults to 1
+    pub denominator: Option<U128>,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct TokenWithRatio {
     pub token_id: AccountId,
-    pub ratio: u32,
+    /// The numerator of the amount of the token's base units per unit of the set
+    pub ratio: U128,
+    /// The denominator of the amount of the token's base units per unit of the set
+    pub denominator: U128,
+    /// The token's decimals, only known if the set was initialized with ratios in whole units
+    pub decimals: Option<u8>,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault, Serialize, Deserialize, Clone)]
@@ -25,3 +33,38 @@ pub struct MetadataReference {
     pub reference: String,
     pub reference_hash: Vec<u8>,
 }
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+/// The optional settings of a token set on initialization. Every setting which is not given
+/// takes its default
+pub struct SetConfig {
+    /// The number of decimals of the set token
+    pub decimals: Option<u8>,
+    /// The amount of set tokens minted for one unit of the underlying ratios
+    pub wrap_to_underlying_ratio: Option<U128>,
+    /// Whether the ratios are in whole units of the underlying tokens, normalized with their
+    /// decimals
+    pub ratios_in_whole_units: Option<bool>,
+    /// The fee for the owner when redeeming set tokens
+    pub owner_redeem_fee: Option<U128>,
+    /// The fee for the platform when redeeming set tokens
+    pub platform_redeem_fee: Option<U128>,
+    /// Whether the redemption fee is taken out of the underlying tokens
+    pub redeem_fee_in_components: Option<bool>,
+    /// The streaming fee for the owner, charged per second on the total supply
+    pub owner_streaming_fee: Option<U128>,
+    /// The streaming fee for the platform, charged per second on the total supply
+    pub platform_streaming_fee: Option<U128>,
+    /// The number of seconds between proposing a fee change and applying it, at least an hour
+    pub fee_change_delay: Option<u64>,
+    /// The maximum amount by which a fee can increase in a single change
+    pub max_fee_increase: Option<U128>,
+    /// The maximum fee the platform can raise its fee to, defaults to the initial platform fee
+    pub max_platform_fee: Option<U128>,
+    /// The number of seconds between proposing a recomposition and executing it, at least an
+    /// hour
+    pub recomposition_notice: Option<u64>,
+    /// The number of seconds a force closed account can claim its escrow for
+    pub escrow_window: Option<u64>,
+}
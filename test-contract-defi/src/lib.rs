@@ -6,7 +6,7 @@ use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::{ValidAccountId, U128};
 use near_sdk::{
     env, ext_contract, log, near_bindgen, setup_alloc, AccountId, Balance, Gas, PanicOnDefault,
-    PromiseOrValue,
+    Promise, PromiseOrValue,
 };
 
 setup_alloc!();
@@ -14,6 +14,8 @@ setup_alloc!();
 const BASE_GAS: Gas = 5_000_000_000_000;
 const PROMISE_CALL: Gas = 5_000_000_000_000;
 const GAS_FOR_FT_ON_TRANSFER: Gas = BASE_GAS + PROMISE_CALL;
+const GAS_FOR_FT_TRANSFER: Gas = BASE_GAS * 2;
+const GAS_FOR_FLASH_MINT: Gas = BASE_GAS + PROMISE_CALL;
 
 const NO_DEPOSIT: Balance = 0;
 
@@ -34,6 +36,23 @@ trait ValueReturnTrait {
     fn value_please(&self, amount_to_return: String) -> PromiseOrValue<U128>;
 }
 
+// The part of the token set contract this contract calls
+#[ext_contract(ext_token_set)]
+pub trait TokenSet {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn flash_mint(&mut self, amount: U128, receiver_id: AccountId, msg: String) -> bool;
+}
+
+trait FlashMintReceiver {
+    fn on_flash_mint(
+        &mut self,
+        sender_id: ValidAccountId,
+        amount: U128,
+        fee: U128,
+        msg: String,
+    ) -> PromiseOrValue<()>;
+}
+
 #[near_bindgen]
 impl DeFi {
     #[init]
@@ -41,6 +60,19 @@ impl DeFi {
         assert!(!env::state_exists(), "Already initialized");
         Self { fungible_token_account_id: fungible_token_account_id.into() }
     }
+
+    /// Flash mint `amount` set tokens to this contract, which the token set only allows to the
+    /// caller itself. The collateral comes from the internal balances of this contract
+    pub fn flash_mint(&mut self, amount: U128, msg: String) -> Promise {
+        ext_token_set::flash_mint(
+            amount,
+            env::current_account_id(),
+            msg,
+            &self.fungible_token_account_id,
+            1,
+            env::prepaid_gas() - GAS_FOR_FLASH_MINT,
+        )
+    }
 }
 
 #[near_bindgen]
@@ -86,3 +118,42 @@ impl ValueReturnTrait for DeFi {
         PromiseOrValue::Value(amount.into())
     }
 }
+
+#[near_bindgen]
+impl FlashMintReceiver for DeFi {
+    /// If given `msg: "keep"`, sends the flash minted tokens to the signer instead of holding
+    /// on to them for the repayment. Otherwise holds on to them and expects to already hold
+    /// the fee
+    fn on_flash_mint(
+        &mut self,
+        sender_id: ValidAccountId,
+        amount: U128,
+        fee: U128,
+        msg: String,
+    ) -> PromiseOrValue<()> {
+        assert_eq!(
+            &env::predecessor_account_id(),
+            &self.fungible_token_account_id,
+            "Only supports the one fungible token contract"
+        );
+        log!(
+            "in on_flash_mint of {} tokens from @{} with a fee of {}, msg = {}",
+            amount.0,
+            sender_id.as_ref(),
+            fee.0,
+            msg
+        );
+        match msg.as_str() {
+            "keep" => ext_token_set::ft_transfer(
+                env::signer_account_id(),
+                amount,
+                None,
+                &self.fungible_token_account_id,
+                1,
+                GAS_FOR_FT_TRANSFER,
+            )
+            .into(),
+            _ => PromiseOrValue::Value(()),
+        }
+    }
+}
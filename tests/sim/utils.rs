@@ -147,7 +147,8 @@ pub fn init_with_macros(
         .enumerate()
         .map(|(i, ft_c)| TokenWithRatioValid {
             token_id: ValidAccountId::try_from(ft_c.account_id()).unwrap(),
-            ratio: ratios[i],
+            ratio: (ratios[i] as u128).into(),
+            denominator: None,
         })
         .collect();
 
@@ -181,6 +182,7 @@ pub fn init_with_macros(
             root.valid_account_id(),
             U128::from(owner_fee.unwrap_or(0)),
             None,
+            None,
             None
         )
     );
@@ -5,7 +5,7 @@ use near_sdk_sim::{
     call, to_yocto, transaction::ExecutionStatus, view, ExecutionResult, DEFAULT_GAS,
 };
 use shared::TokenWithRatioValid;
-use token_set_fungible_token::{SetMetadata, WRAP_TO_UNDERLYING_RATIO};
+use token_set_fungible_token::{AuctionStatus, SetMetadata, WRAP_TO_UNDERLYING_RATIO};
 
 use crate::utils::{init_with_macros as init, register_user};
 
@@ -42,14 +42,27 @@ fn simulate_deploying_contract() {
             set_symbol.clone(),
             None,
             vec![
-                TokenWithRatioValid { token_id: fts[0].valid_account_id(), ratio: 1 },
-                TokenWithRatioValid { token_id: fts[1].valid_account_id(), ratio: 2 },
-                TokenWithRatioValid { token_id: fts[2].valid_account_id(), ratio: 4 }
+                TokenWithRatioValid {
+                    token_id: fts[0].valid_account_id(),
+                    ratio: 1.into(),
+                    denominator: None
+                },
+                TokenWithRatioValid {
+                    token_id: fts[1].valid_account_id(),
+                    ratio: 2.into(),
+                    denominator: None
+                },
+                TokenWithRatioValid {
+                    token_id: fts[2].valid_account_id(),
+                    ratio: 4.into(),
+                    denominator: None
+                }
             ],
             0.into(),
             root.valid_account_id(),
             0.into(),
             None,
+            None,
             None
         ),
         deposit = 1
@@ -78,7 +91,7 @@ fn simulate_overwrapping() {
     let (root, owner_bob, token_set, _, _deployer, fts, alice) =
         init(vec![1, 2, 4], Some(0), Some(0), initial_balance);
 
-    call!(alice, token_set.wrap(Some(100.into())), deposit = 1).assert_success();
+    call!(alice, token_set.wrap(Some(100.into()), None), deposit = 1).assert_success();
 }
 
 #[test]
@@ -121,7 +134,7 @@ fn simulate_wrapping() {
         // Check the balances successfully transferred
         assert_eq!(tok_bal.0, initial_balance);
     });
-    call!(alice, token_set.wrap(None), deposit = 1).assert_success();
+    call!(alice, token_set.wrap(None, None), deposit = 1).assert_success();
 
     let amount_minted = initial_balance / 4 * WRAP_TO_UNDERLYING_RATIO;
     let amount_minted_scaled_down = initial_balance / 4;
@@ -160,11 +173,13 @@ fn simulate_wrapping() {
     let total_supply: U128 = view!(token_set.ft_total_supply()).unwrap_json();
     assert_eq!(total_supply.0, amount_minted);
 
-    // Unwrap
+    // Unwrap, rounding down to the wrap to underlying ratio
     let expected_alice_rounded =
         expected_alice / WRAP_TO_UNDERLYING_RATIO * WRAP_TO_UNDERLYING_RATIO;
-    call!(alice, token_set.unwrap(U128::from(expected_alice_rounded)), deposit = 1)
-        .assert_success();
+    let exec = call!(alice, token_set.unwrap(U128::from(expected_alice), Some(true)), deposit = 1);
+    exec.assert_success();
+    let burned: U128 = exec.unwrap_json();
+    assert_eq!(burned.0, expected_alice_rounded);
 
     let total_supply: U128 = view!(token_set.ft_total_supply()).unwrap_json();
     assert_eq!(total_supply.0, amount_minted - expected_alice_rounded);
@@ -185,6 +200,179 @@ fn simulate_wrapping() {
     });
 }
 
+#[test]
+fn simulate_auction_rebalancing() {
+    let initial_balance = 1_000;
+    let ratios = vec![1, 2, 4];
+    let (root, owner_bob, token_set, _, _deployer, fts, alice) =
+        init(ratios.clone(), Some(0), Some(0), initial_balance);
+
+    fts.iter().for_each(|ft| {
+        call!(
+            root,
+            ft.ft_transfer(alice.valid_account_id(), initial_balance.into(), None),
+            deposit = 1
+        )
+        .assert_success();
+        call!(
+            alice,
+            ft.ft_transfer_call(
+                token_set.valid_account_id(),
+                initial_balance.into(),
+                None,
+                json!({"sender_id": alice.account_id()}).to_string()
+            ),
+            deposit = 1
+        )
+        .assert_success();
+    });
+    call!(alice, token_set.wrap(None, None), deposit = 1).assert_success();
+    // 250 units of the set are backed by 250 of ft-0, 500 of ft-1 and 1000 of ft-2
+
+    // Sell 100 of ft-0 for ft-1, asking from 200 down to 100 over an hour
+    call!(
+        owner_bob,
+        token_set.start_auction(
+            fts[0].valid_account_id(),
+            100.into(),
+            fts[1].valid_account_id(),
+            200.into(),
+            100.into(),
+            60 * 60
+        ),
+        deposit = 1
+    )
+    .assert_success();
+    // Wrapping is blocked while the auction runs
+    let exec = call!(alice, token_set.wrap(None, None), deposit = 1);
+    assert!(!exec.is_ok());
+
+    // Bid more than needed for the whole lot by transfer, the rest is refunded
+    let root_ft_1_before: U128 = view!(fts[1].ft_balance_of(root.valid_account_id())).unwrap_json();
+    call!(
+        root,
+        fts[1].ft_transfer_call(
+            token_set.valid_account_id(),
+            250.into(),
+            None,
+            json!({"action": "bid", "min_amount_out": "100"}).to_string()
+        ),
+        deposit = 1
+    )
+    .assert_success();
+
+    let status: Option<AuctionStatus> = view!(token_set.get_auction()).unwrap_json();
+    let auction = status.unwrap().auction;
+    assert_eq!(auction.sold.0, 100);
+    let bought = auction.bought.0;
+    assert!(bought > 100 && bought <= 200);
+    let root_ft_1_after: U128 = view!(fts[1].ft_balance_of(root.valid_account_id())).unwrap_json();
+    assert_eq!(root_ft_1_before.0 - root_ft_1_after.0, bought);
+    let root_ft_0: U128 =
+        view!(token_set.get_ft_balance(root.valid_account_id(), fts[0].valid_account_id()))
+            .unwrap_json();
+    assert_eq!(root_ft_0.0, 100);
+
+    // The auction sold out, so anyone can settle it
+    call!(alice, token_set.settle_auction()).assert_success();
+    let metadata: SetMetadata = view!(token_set.set_metadata()).unwrap_json();
+    let value = serde_json::to_value(&metadata).unwrap();
+    let set_ratios = value["set_ratios"].as_array().unwrap();
+    assert_eq!(set_ratios[0]["ratio"], "3");
+    assert_eq!(set_ratios[0]["denominator"], "5");
+    assert_eq!(set_ratios[2]["ratio"], "4");
+
+    // Unwrapping releases the tokens under the new ratios
+    let alice_ft_0: U128 =
+        view!(token_set.get_ft_balance(alice.valid_account_id(), fts[0].valid_account_id()))
+            .unwrap_json();
+    call!(alice, token_set.unwrap((5 * WRAP_TO_UNDERLYING_RATIO).into(), None), deposit = 1)
+        .assert_success();
+    let alice_ft_0_after: U128 =
+        view!(token_set.get_ft_balance(alice.valid_account_id(), fts[0].valid_account_id()))
+            .unwrap_json();
+    assert_eq!(alice_ft_0_after.0 - alice_ft_0.0, 3);
+}
+
+#[test]
+fn simulate_flash_mint() {
+    let initial_balance = 1_000;
+    let (root, owner_bob, token_set, defi, _deployer, fts, alice) =
+        init(vec![1, 2, 4], Some(0), Some(0), initial_balance);
+    register_user(&vec![], &defi.user_account, true);
+    // The components are credited to the defi contract, as collateral for its flash mints
+    fts.iter().for_each(|ft| {
+        call!(
+            root,
+            ft.ft_transfer(alice.valid_account_id(), initial_balance.into(), None),
+            deposit = 1
+        )
+        .assert_success();
+        call!(
+            alice,
+            ft.ft_transfer_call(
+                token_set.valid_account_id(),
+                initial_balance.into(),
+                None,
+                json!({"sender_id": defi.user_account.account_id()}).to_string()
+            ),
+            deposit = 1
+        )
+        .assert_success();
+    });
+    // The defi contract wraps one unit to pay the fees from
+    call!(
+        defi.user_account,
+        token_set.wrap(Some(WRAP_TO_UNDERLYING_RATIO.into()), None),
+        deposit = 1
+    )
+    .assert_success();
+
+    let amount = 100 * WRAP_TO_UNDERLYING_RATIO;
+    // A 0.1% fee, so the defi contract has to pay one set token per thousand
+    call!(owner_bob, token_set.set_flash_mint_fee(Some(1_000_000_000_000.into())), deposit = 1)
+        .assert_success();
+    let fee = amount / 1_000;
+
+    // Only the receiver itself can flash mint
+    let exec = call!(
+        alice,
+        token_set.flash_mint(amount.into(), defi.valid_account_id(), "".to_string()),
+        deposit = 1
+    );
+    assert!(!exec.is_ok());
+
+    let supply_before: U128 = view!(token_set.ft_total_supply()).unwrap_json();
+    let exec = call!(alice, defi.flash_mint(amount.into(), "".to_string()));
+    println!("LOGS: {:?}", exec.logs());
+    exec.assert_success();
+    let repaid: bool = exec.unwrap_json();
+    assert!(repaid);
+    let defi_balance: U128 = view!(token_set.ft_balance_of(defi.valid_account_id())).unwrap_json();
+    assert_eq!(defi_balance.0, WRAP_TO_UNDERLYING_RATIO - fee);
+    let supply_after: U128 = view!(token_set.ft_total_supply()).unwrap_json();
+    assert_eq!(supply_before.0 - supply_after.0, fee);
+    // The collateral is back in the internal balance of the defi contract
+    let defi_ft_2: U128 =
+        view!(token_set.get_ft_balance(defi.valid_account_id(), fts[2].valid_account_id()))
+            .unwrap_json();
+    assert_eq!(defi_ft_2.0, initial_balance - 4);
+
+    // The defi contract sends the minted tokens to alice instead of repaying them, so the
+    // flash mint stands as a wrap of its collateral
+    let exec = call!(alice, defi.flash_mint(amount.into(), "keep".to_string()));
+    exec.assert_success();
+    let repaid: bool = exec.unwrap_json();
+    assert!(!repaid);
+    let alice_balance: U128 =
+        view!(token_set.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(alice_balance.0, amount);
+    let defi_ft_2: U128 =
+        view!(token_set.get_ft_balance(defi.valid_account_id(), fts[2].valid_account_id()))
+            .unwrap_json();
+    assert_eq!(defi_ft_2.0, initial_balance - 4 - 400);
+}
+
 // #[test]
 // fn simulate_simple_transfer() {
 //     let transfer_amount = to_yocto("100");
@@ -2,19 +2,90 @@ use near_account::{AccountInfoTrait, NewInfo};
 use near_internal_balances_plugin::BalanceInfo;
 use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize},
-    collections::UnorderedMap,
-    AccountId, Balance,
+    collections::{LazyOption, LookupMap, UnorderedMap},
+    AccountId, Balance, IntoStorageKey,
 };
+use std::ops::Deref;
+
+pub(crate) const INTERNAL_TOTALS_PREFIX: &[u8] = b"internal-totals";
+pub(crate) const INTERNAL_TOTALS_SEEDING_KEY: &[u8] = b"internal-totals-seeding";
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct AccountInfo {
-    pub internal_balance: UnorderedMap<AccountId, Balance>,
+    pub internal_balance: InternalBalances,
 }
 
 impl NewInfo for AccountInfo {
     fn default_from_account_id(account_id: AccountId) -> Self {
-        Self { internal_balance: UnorderedMap::new(format!("{}-bals", account_id).as_bytes()) }
+        Self { internal_balance: InternalBalances::new(format!("{}-bals", account_id)) }
     }
 }
 
 impl AccountInfoTrait for AccountInfo {}
+
+/// The internal balances of one account. Every change is also applied to the running total of
+/// the token over all accounts, including the deposits and withdrawals of the plugin, so the
+/// totals never have to be summed up over the accounts. The plugin changes balances without
+/// access to the contract, so the `internal_totals` field is written through a map on its
+/// prefix.
+///
+/// It is stored exactly like the map it wraps
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct InternalBalances {
+    balances: UnorderedMap<AccountId, Balance>,
+}
+
+impl InternalBalances {
+    pub fn new<S: IntoStorageKey>(prefix: S) -> Self {
+        Self { balances: UnorderedMap::new(prefix) }
+    }
+
+    pub fn insert(&mut self, token_id: &AccountId, balance: &Balance) -> Option<Balance> {
+        assert_internal_totals_seeded();
+        let previous = self.balances.insert(token_id, balance);
+        update_internal_total(token_id, previous.unwrap_or(0), *balance);
+        previous
+    }
+
+    pub fn remove(&mut self, token_id: &AccountId) -> Option<Balance> {
+        assert_internal_totals_seeded();
+        let previous = self.balances.remove(token_id);
+        if let Some(previous) = previous {
+            update_internal_total(token_id, previous, 0);
+        }
+        previous
+    }
+}
+
+/// Only reads go to the map directly, so no change can skip the totals
+impl Deref for InternalBalances {
+    type Target = UnorderedMap<AccountId, Balance>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.balances
+    }
+}
+
+/// The `internal_totals_seeding` field of the contract, read here so that no internal balance
+/// changes while a migrated set seeds the totals: the change could not be told apart from the
+/// balances still to be seeded
+fn assert_internal_totals_seeded() {
+    if LazyOption::<u64>::new(INTERNAL_TOTALS_SEEDING_KEY, None).is_some() {
+        panic!("Expected the internal balance totals to be seeded first");
+    }
+}
+
+fn update_internal_total(token_id: &AccountId, previous: Balance, balance: Balance) {
+    if previous == balance {
+        return;
+    }
+    let mut totals: LookupMap<AccountId, Balance> = LookupMap::new(INTERNAL_TOTALS_PREFIX);
+    let total = (totals.get(token_id).unwrap_or(0) + balance)
+        .checked_sub(previous)
+        .unwrap_or_else(|| panic!("Internal balance total underflow for {}", token_id));
+    if total == 0 {
+        totals.remove(token_id);
+    } else {
+        totals.insert(token_id, &total);
+    }
+}
@@ -0,0 +1,353 @@
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId, Balance};
+use shared::TokenWithRatio;
+
+use crate::token_set_info::FEE_DENOMINATOR;
+use crate::utils::{mul_div, reduce_fraction, timestamp_after, U256};
+use crate::{Contract, ContractContract, SetInfo};
+
+/// The maximum number of seconds an auction can run for
+pub const MAX_AUCTION_DURATION: u64 = 7 * 24 * 60 * 60;
+/// The maximum discount of the end buy amount on the start buy amount, 50%
+pub const MAX_AUCTION_DISCOUNT: u128 = 500_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+/// A Dutch auction of part of the backing of one token for another token. The amount of the
+/// buy token asked for the whole lot decays linearly from `start_buy_amount` to
+/// `end_buy_amount`
+pub struct Auction {
+    pub sell_token_id: AccountId,
+    pub buy_token_id: AccountId,
+    /// The amount of the sell token offered
+    pub sell_amount: U128,
+    pub start_buy_amount: U128,
+    pub end_buy_amount: U128,
+    /// The amount of the sell token sold so far
+    pub sold: U128,
+    /// The amount of the buy token bought so far
+    pub bought: U128,
+    /// The block timestamps, in nanoseconds, of the start and the end of the auction
+    pub start_at: U64,
+    pub end_at: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionStatus {
+    pub auction: Auction,
+    /// The amount of the buy token currently asked for the whole lot
+    pub current_buy_amount: U128,
+}
+
+impl Auction {
+    /// The amount of the buy token asked for the whole lot at `timestamp`
+    fn buy_amount_at(&self, timestamp: u64) -> Balance {
+        if timestamp >= self.end_at.0 {
+            return self.end_buy_amount.0;
+        }
+        let elapsed = timestamp.saturating_sub(self.start_at.0) as u128;
+        let duration = (self.end_at.0 - self.start_at.0) as u128;
+        self.start_buy_amount.0
+            - mul_div(self.start_buy_amount.0 - self.end_buy_amount.0, elapsed, duration, false)
+                .unwrap()
+    }
+
+    fn is_sold_out(&self) -> bool {
+        self.sold.0 == self.sell_amount.0
+    }
+}
+
+impl SetInfo {
+    pub(crate) fn assert_no_auction(&self) {
+        if self.auction.is_some() {
+            panic!("The set is being rebalanced by an auction");
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Auction `sell_amount` of the backing in `sell_token_id` for `buy_token_id` over
+    /// `duration` seconds, at most `MAX_AUCTION_DURATION`. The end buy amount can be at most
+    /// `MAX_AUCTION_DISCOUNT` below the start buy amount, and the owner cannot bid.
+    ///
+    /// Wrapping is blocked until the auction is settled. Unwrapping stays open and releases
+    /// a share of the whole locked backing, including the bought token, and takes the same
+    /// share out of the lot
+    #[payable]
+    pub fn start_auction(
+        &mut self,
+        sell_token_id: ValidAccountId,
+        sell_amount: U128,
+        buy_token_id: ValidAccountId,
+        start_buy_amount: U128,
+        end_buy_amount: U128,
+        duration: u64,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.set_info.assert_ready();
+        self.set_info.assert_no_auction();
+        self.set_info.assert_no_flash_mint();
+        if sell_token_id == buy_token_id {
+            panic!("Expected the sell and buy tokens to be different");
+        }
+        if sell_amount.0 == 0 || end_buy_amount.0 == 0 || duration == 0 {
+            panic!("Expected a non-zero sell amount, buy amount and duration");
+        }
+        if start_buy_amount.0 < end_buy_amount.0 {
+            panic!("Expected the start buy amount to be at least the end buy amount");
+        }
+        if duration > MAX_AUCTION_DURATION {
+            panic!("Expected the duration to be at most {} seconds", MAX_AUCTION_DURATION);
+        }
+        let min_end_buy_amount = start_buy_amount.0
+            - mul_div(start_buy_amount.0, MAX_AUCTION_DISCOUNT, FEE_DENOMINATOR, false).unwrap();
+        if end_buy_amount.0 < min_end_buy_amount {
+            panic!("Expected the end buy amount to be at least {}", min_end_buy_amount);
+        }
+        let backing = self.locked_backing_of(sell_token_id.as_ref());
+        if sell_amount.0 > backing {
+            panic!("Expected the sell amount to be at most the backing of {}", backing);
+        }
+
+        let now = env::block_timestamp();
+        let auction = Auction {
+            sell_token_id: sell_token_id.into(),
+            buy_token_id: buy_token_id.into(),
+            sell_amount,
+            start_buy_amount,
+            end_buy_amount,
+            sold: 0.into(),
+            bought: 0.into(),
+            start_at: now.into(),
+            end_at: timestamp_after(duration).into(),
+        };
+        log!(
+            "Started an auction of {} {} for {} to {} {}",
+            auction.sell_amount.0,
+            auction.sell_token_id,
+            auction.start_buy_amount.0,
+            auction.end_buy_amount.0,
+            auction.buy_token_id
+        );
+        self.set_info.auction = Some(auction);
+    }
+
+    /// Settle the auction and update the ratios with what was sold and bought, or keep the
+    /// ratios if the whole supply was unwrapped during the auction.
+    /// Can be called by anyone once the auction ended or sold out, or by the owner at any time
+    pub fn settle_auction(&mut self) {
+        let auction = self.set_info.auction.take().unwrap_or_else(|| panic!("There is no auction"));
+        if env::block_timestamp() < auction.end_at.0
+            && !auction.is_sold_out()
+            && env::predecessor_account_id() != self.owner_id
+        {
+            panic!("The auction can only be settled by the owner before it ends");
+        }
+
+        let units = self.supply_units();
+        // With no supply left there is nothing to derive the ratios from, so keep the old ones
+        if units == 0 {
+            log!("Settled the auction with no supply left, keeping the ratios");
+            return;
+        }
+        // The locked backing already includes what was sold and bought
+        let ratios: Vec<TokenWithRatio> = self
+            .backing_token_ids(&auction)
+            .into_iter()
+            .map(|token_id| {
+                let backing = self.locked_backing_of(&token_id);
+                (token_id, backing)
+            })
+            .filter(|(_, backing)| *backing > 0)
+            .map(|(token_id, backing)| {
+                let (numerator, denominator) =
+                    reduce_fraction(U256::from(backing), U256::from(units));
+                TokenWithRatio {
+                    token_id,
+                    ratio: U128(numerator.as_u128()),
+                    denominator: U128(denominator.as_u128()),
+                    decimals: None,
+                }
+            })
+            .collect();
+        self.replace_ratios(ratios);
+        log!(
+            "Settled the auction, sold {} {} for {} {}",
+            auction.sold.0,
+            auction.sell_token_id,
+            auction.bought.0,
+            auction.buy_token_id
+        );
+    }
+
+    /// Bid up to `amount` of the buy token from the caller's internal balance in the running
+    /// auction. The sell token bought is credited to its internal balance. Only the part of the
+    /// bid needed to fill the auction is taken.
+    ///
+    /// Bids can also be made by transferring the buy token with `ft_transfer_call` and the
+    /// `{"action":"bid"}` msg, which refunds the unused part of the transfer
+    ///
+    /// return the amount of the sell token bought
+    #[payable]
+    pub fn bid(&mut self, amount: U128, min_amount_out: Option<U128>) -> U128 {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.bid_internal(&account_id, amount.into(), min_amount_out.map(|a| a.0)).into()
+    }
+
+    pub fn get_auction(&self) -> Option<AuctionStatus> {
+        self.set_info.auction.clone().map(|auction| AuctionStatus {
+            current_buy_amount: auction.buy_amount_at(env::block_timestamp()).into(),
+            auction,
+        })
+    }
+}
+
+impl Contract {
+    /// Fill the auction with up to `amount` of the buy token from the internal balance of
+    /// `account_id`, crediting the sell token to the same internal balance
+    ///
+    /// return the amount of the sell token bought
+    pub(crate) fn bid_internal(
+        &mut self,
+        account_id: &AccountId,
+        amount: Balance,
+        min_amount_out: Option<Balance>,
+    ) -> Balance {
+        let (amount_used, amount_out) = self.fill_auction(account_id, amount, min_amount_out);
+        let auction = self.set_info.auction.clone().unwrap();
+        self.subtract_balance(account_id, &auction.buy_token_id, amount_used);
+        self.increase_balance(account_id, &auction.sell_token_id, amount_out);
+        amount_out
+    }
+
+    /// Fill the auction with `amount` of the buy token transferred from `token_id` by
+    /// `sender_id`, crediting the sell token to the internal balance of `receiver_id`
+    ///
+    /// return the unused amount of the buy token
+    pub(crate) fn on_bid(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+        min_amount_out: Option<Balance>,
+    ) -> Balance {
+        self.assert_internal_account_registered(receiver_id);
+        let auction =
+            self.set_info.auction.clone().unwrap_or_else(|| panic!("There is no auction"));
+        if token_id != &auction.buy_token_id {
+            panic!("Expected a bid in {}", auction.buy_token_id);
+        }
+        let (amount_used, amount_out) = self.fill_auction(sender_id, amount, min_amount_out);
+        self.increase_balance(receiver_id, &auction.sell_token_id, amount_out);
+        amount - amount_used
+    }
+
+    /// Sell to `bidder_id` what `amount` of the buy token is worth at the current price, moving
+    /// the sold and bought tokens in and out of the locked backing. Only the part of `amount`
+    /// needed to fill the auction is used
+    ///
+    /// return the amount of the buy token used and the amount of the sell token bought
+    fn fill_auction(
+        &mut self,
+        bidder_id: &AccountId,
+        amount: Balance,
+        min_amount_out: Option<Balance>,
+    ) -> (Balance, Balance) {
+        let mut auction =
+            self.set_info.auction.clone().unwrap_or_else(|| panic!("There is no auction"));
+        if bidder_id == &self.owner_id {
+            panic!("The owner cannot bid in its own auction");
+        }
+        let now = env::block_timestamp();
+        if now >= auction.end_at.0 || auction.is_sold_out() {
+            panic!("The auction has ended");
+        }
+
+        let buy_amount = auction.buy_amount_at(now);
+        let sell_amount = auction.sell_amount.0;
+        let remaining = sell_amount - auction.sold.0;
+        let mut amount_out = mul_div(amount, sell_amount, buy_amount, false).unwrap();
+        let mut amount_used = amount;
+        if amount_out >= remaining {
+            amount_out = remaining;
+            amount_used = mul_div(remaining, buy_amount, sell_amount, true).unwrap();
+        }
+        if amount_out == 0 {
+            panic!("The bid is too small");
+        }
+        if let Some(min_amount_out) = min_amount_out {
+            if amount_out < min_amount_out {
+                panic!("Expected at least {} out, got {}", min_amount_out, amount_out);
+            }
+        }
+
+        auction.sold = (auction.sold.0 + amount_out).into();
+        auction.bought = (auction.bought.0 + amount_used).into();
+        self.release_backing(&auction.sell_token_id, amount_out);
+        self.lock_backing(&auction.buy_token_id, amount_used);
+        log!(
+            "@{} bought {} {} for {} {}",
+            bidder_id,
+            amount_out,
+            auction.sell_token_id,
+            amount_used,
+            auction.buy_token_id
+        );
+        self.set_info.auction = Some(auction);
+        (amount_used, amount_out)
+    }
+
+    /// The tokens of the ratios and the buy token of `auction`
+    fn backing_token_ids(&self, auction: &Auction) -> Vec<AccountId> {
+        let mut token_ids: Vec<AccountId> =
+            self.set_info.ratios.iter().map(|ratio| ratio.token_id).collect();
+        if !token_ids.contains(&auction.buy_token_id) {
+            token_ids.push(auction.buy_token_id.clone());
+        }
+        token_ids
+    }
+
+    /// The share of the locked backing of each token of the running auction released for
+    /// `units` out of `total_units` units of the set, as the backing does not follow the ratios
+    /// during the auction
+    pub(crate) fn auction_released_amounts(
+        &self,
+        auction: &Auction,
+        units: Balance,
+        total_units: Balance,
+    ) -> Vec<(AccountId, Balance)> {
+        self.backing_token_ids(auction)
+            .into_iter()
+            .map(|token_id| {
+                let amount =
+                    mul_div(self.locked_backing_of(&token_id), units, total_units, false).unwrap();
+                (token_id, amount)
+            })
+            .collect()
+    }
+
+    /// Take the share of `units` out of `total_units` units of the set out of what is left of
+    /// the lot of the running auction, keeping its price
+    pub(crate) fn shrink_auction(&mut self, units: Balance, total_units: Balance) {
+        if let Some(mut auction) = self.set_info.auction.take() {
+            let remaining = auction.sell_amount.0 - auction.sold.0;
+            let taken = mul_div(remaining, units, total_units, true).unwrap();
+            let sell_amount = auction.sell_amount.0 - taken;
+            let scale = |buy_amount: U128| {
+                U128(mul_div(buy_amount.0, sell_amount, auction.sell_amount.0, true).unwrap())
+            };
+            auction.start_buy_amount = scale(auction.start_buy_amount);
+            auction.end_buy_amount = scale(auction.end_buy_amount);
+            auction.sell_amount = sell_amount.into();
+            self.set_info.auction = Some(auction);
+        }
+    }
+}
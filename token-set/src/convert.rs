@@ -0,0 +1,315 @@
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde_json::{self, json};
+use near_sdk::{
+    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, Gas, Promise, PromiseOrValue,
+    PromiseResult,
+};
+
+use crate::events::emit_ft_mint;
+use crate::external::{
+    ext_ft, ext_internal_balances, ext_self, ext_set, BASE_GAS, GAS_FOR_CONVERT_TO,
+    GAS_FOR_FT_TRANSFER_CALL, GAS_FOR_RESOLVE_CONVERT_ROLLBACK, GAS_FOR_SET_METADATA,
+    GAS_FOR_WITHDRAW_TO, GAS_FOR_WRAP_TO,
+};
+use crate::native::is_native_near;
+use crate::token_set_info::ratio_amount;
+use crate::utils::mul_div;
+use crate::{Contract, ContractContract, SetMetadata, TokenAmount};
+
+/// The gas for withdrawing `n` tokens back from the target set and resolving the rollback
+fn gas_for_rollback(n: usize) -> Gas {
+    n as Gas * GAS_FOR_WITHDRAW_TO + GAS_FOR_RESOLVE_CONVERT_ROLLBACK + BASE_GAS
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Convert `amount` of the caller's set tokens into tokens of `target_set`.
+    ///
+    /// The set tokens are unwrapped like with `unwrap`, and the components which `target_set`
+    /// needs for as many of its units as possible are deposited into the internal balance of
+    /// this set on `target_set`. This set then wraps them with `wrap_to` for the caller, so
+    /// nothing is credited on the caller's word. The rest of the released tokens stays in the
+    /// caller's internal balance.
+    ///
+    /// The caller has to be registered for internal balances here and for the set token on
+    /// `target_set`, and this set has to be registered for internal balances on `target_set`.
+    ///
+    /// If `target_set` refuses a deposit or the wrap, the deposited tokens are withdrawn back
+    /// and the burned set tokens are wrapped again for the caller, without a mint fee. The
+    /// redemption fee is not refunded
+    #[payable]
+    pub fn convert_to(&mut self, target_set: ValidAccountId, amount: U128) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let target_set: AccountId = target_set.into();
+        if target_set == env::current_account_id() {
+            panic!("Expected the target set to be another set");
+        }
+        self.assert_internal_account_registered(&account_id);
+
+        ext_set::set_metadata(&target_set, 0, GAS_FOR_SET_METADATA).then(
+            ext_self::resolve_convert_to(
+                account_id,
+                target_set,
+                amount,
+                &env::current_account_id(),
+                0,
+                env::prepaid_gas() - GAS_FOR_CONVERT_TO,
+            ),
+        )
+    }
+
+    /// Unwrap with the ratios of the target set known and deposit its components into it
+    #[private]
+    pub fn resolve_convert_to(
+        &mut self,
+        account_id: AccountId,
+        target_set: AccountId,
+        amount: U128,
+    ) -> Promise {
+        let target: SetMetadata = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice(&value)
+                .unwrap_or_else(|_| panic!("Invalid set metadata from {}", target_set)),
+            _ => panic!("Failed to get the set metadata of {}", target_set),
+        };
+        if target.set_ratios.iter().any(|ratio| is_native_near(&ratio.token_id)) {
+            panic!("Converting into a set with native NEAR is not supported");
+        }
+
+        let burned = self.redemption(&self.accrued_pricing(), amount.into()).burned;
+        let released = self.redeem(&account_id, amount.into());
+        for (token_id, amount_underlying) in released.iter() {
+            self.increase_balance(&account_id, token_id, *amount_underlying);
+        }
+        let released_of = |token_id: &AccountId| -> Balance {
+            released.iter().find(|(t, _)| t == token_id).map(|(_, a)| *a).unwrap_or(0)
+        };
+
+        // The largest number of units of the target set the released tokens are enough for
+        let units = target
+            .set_ratios
+            .iter()
+            .map(|ratio| {
+                mul_div(released_of(&ratio.token_id), ratio.denominator.0, ratio.ratio.0, false)
+                    .unwrap_or(u128::MAX)
+            })
+            .min()
+            .unwrap_or(0);
+        if units == 0 {
+            panic!("The released tokens are not enough for a unit of {}", target_set);
+        }
+        let amount_target = units
+            .checked_mul(target.wrap_to_underlying_ratio.0)
+            .unwrap_or_else(|| panic!("The amount of {} overflows", target_set));
+        log!("Converting into {} of {} for @{}", amount_target, target_set, account_id);
+
+        // The deposits are credited to this set, which is the actual sender
+        let msg = json!({ "sender_id": env::current_account_id() }).to_string();
+        let mut deposits = vec![];
+        let mut promise: Option<Promise> = None;
+        for ratio in target.set_ratios.iter() {
+            let amount_underlying = ratio_amount(ratio, units, true);
+            self.subtract_balance(&account_id, &ratio.token_id, amount_underlying);
+            let transfer = ext_ft::ft_transfer_call(
+                target_set.clone(),
+                amount_underlying.into(),
+                None,
+                msg.clone(),
+                &ratio.token_id,
+                1,
+                GAS_FOR_FT_TRANSFER_CALL,
+            );
+            promise = Some(match promise {
+                Some(promise) => promise.and(transfer),
+                None => transfer,
+            });
+            deposits.push(TokenAmount {
+                token_id: ratio.token_id.clone(),
+                amount: amount_underlying.into(),
+            });
+        }
+        let gas = GAS_FOR_WRAP_TO + BASE_GAS * 2 + gas_for_rollback(deposits.len());
+        promise.unwrap().then(ext_self::resolve_convert_deposits(
+            account_id,
+            target_set,
+            deposits,
+            amount_target.into(),
+            burned.into(),
+            &env::current_account_id(),
+            0,
+            gas,
+        ))
+    }
+
+    /// Wrap the deposits on the target set for the caller if all of them were accepted, or
+    /// roll back the conversion otherwise
+    #[private]
+    pub fn resolve_convert_deposits(
+        &mut self,
+        account_id: AccountId,
+        target_set: AccountId,
+        deposits: Vec<TokenAmount>,
+        amount_target: U128,
+        burned: U128,
+    ) -> PromiseOrValue<U128> {
+        let mut accepted = vec![];
+        for (i, deposit) in deposits.iter().enumerate() {
+            let amount: Balance = deposit.amount.into();
+            // `ft_transfer_call` returns the amount used by the receiver, the rest was refunded
+            let used = match env::promise_result(i as u64) {
+                PromiseResult::Successful(value) => {
+                    serde_json::from_slice::<U128>(&value).map(|u| u.0).unwrap_or(amount)
+                }
+                _ => 0,
+            };
+            let used = std::cmp::min(used, amount);
+            if used < amount {
+                self.increase_balance(&account_id, &deposit.token_id, amount - used);
+            }
+            if used > 0 {
+                accepted
+                    .push(TokenAmount { token_id: deposit.token_id.clone(), amount: used.into() });
+            }
+        }
+
+        let all_accepted = accepted.len() == deposits.len()
+            && accepted.iter().zip(deposits.iter()).all(|(a, d)| a.amount.0 == d.amount.0);
+        if all_accepted {
+            let gas = BASE_GAS + gas_for_rollback(deposits.len());
+            return PromiseOrValue::Promise(
+                ext_set::wrap_to(
+                    account_id.clone(),
+                    Some(amount_target),
+                    None,
+                    &target_set,
+                    1,
+                    GAS_FOR_WRAP_TO,
+                )
+                .then(ext_self::resolve_convert_wrap(
+                    account_id,
+                    target_set,
+                    deposits,
+                    burned,
+                    &env::current_account_id(),
+                    0,
+                    gas,
+                )),
+            );
+        }
+        log!("@{} refused a deposit, rolling back the conversion", target_set);
+        self.withdraw_conversion(account_id, target_set, accepted, burned)
+    }
+
+    /// Finish the conversion once the target set wrapped the deposits, or roll it back
+    ///
+    /// return the amount of target set tokens minted to the caller
+    #[private]
+    pub fn resolve_convert_wrap(
+        &mut self,
+        account_id: AccountId,
+        target_set: AccountId,
+        deposits: Vec<TokenAmount>,
+        burned: U128,
+    ) -> PromiseOrValue<U128> {
+        match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                let wrapped = serde_json::from_slice::<U128>(&value).unwrap_or(U128(0));
+                log!("Converted into {} of {} for @{}", wrapped.0, target_set, account_id);
+                PromiseOrValue::Value(wrapped)
+            }
+            _ => {
+                log!("@{} refused to wrap, rolling back the conversion", target_set);
+                self.withdraw_conversion(account_id, target_set, deposits, burned)
+            }
+        }
+    }
+
+    /// Credit the tokens withdrawn back from the target set to the caller and wrap the burned
+    /// set tokens again. A withdrawal which failed stays in the internal balance of this set on
+    /// the target set
+    ///
+    /// return the amount of set tokens given back to the caller
+    #[private]
+    pub fn resolve_convert_rollback(
+        &mut self,
+        account_id: AccountId,
+        target_set: AccountId,
+        withdrawals: Vec<TokenAmount>,
+        burned: U128,
+    ) -> U128 {
+        for (i, withdrawal) in withdrawals.into_iter().enumerate() {
+            match env::promise_result(i as u64) {
+                PromiseResult::Successful(_) => {
+                    self.increase_balance(&account_id, &withdrawal.token_id, withdrawal.amount.0)
+                }
+                _ => log!(
+                    "Failed to withdraw {} of {} from @{}",
+                    withdrawal.amount.0,
+                    withdrawal.token_id,
+                    target_set
+                ),
+            }
+        }
+        self.restore_burned(&account_id, burned.into()).into()
+    }
+}
+
+impl Contract {
+    /// Withdraw the deposits accepted by the target set back to this set and roll back the
+    /// conversion once they arrive
+    fn withdraw_conversion(
+        &mut self,
+        account_id: AccountId,
+        target_set: AccountId,
+        withdrawals: Vec<TokenAmount>,
+        burned: U128,
+    ) -> PromiseOrValue<U128> {
+        if withdrawals.is_empty() {
+            return PromiseOrValue::Value(self.restore_burned(&account_id, burned.into()).into());
+        }
+        let mut promise: Option<Promise> = None;
+        for withdrawal in withdrawals.iter() {
+            let withdraw = ext_internal_balances::withdraw_to(
+                withdrawal.amount,
+                withdrawal.token_id.clone(),
+                None,
+                None,
+                &target_set,
+                1,
+                GAS_FOR_WITHDRAW_TO,
+            );
+            promise = Some(match promise {
+                Some(promise) => promise.and(withdraw),
+                None => withdraw,
+            });
+        }
+        PromiseOrValue::Promise(promise.unwrap().then(ext_self::resolve_convert_rollback(
+            account_id,
+            target_set,
+            withdrawals,
+            burned,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_CONVERT_ROLLBACK,
+        )))
+    }
+
+    /// Wrap up to `burned` set tokens again for `account_id` from its internal balance after a
+    /// failed conversion, without a mint fee
+    ///
+    /// return the amount of set tokens wrapped
+    fn restore_burned(&mut self, account_id: &AccountId, burned: Balance) -> Balance {
+        let amount = std::cmp::min(
+            self.round_to_wrap_ratio(burned),
+            self.get_max_amount(&self.pricing(), account_id),
+        );
+        if amount == 0 {
+            return 0;
+        }
+        self.decrease_potentials(amount / self.set_info.wrap_to_underlying_ratio, account_id);
+        self.token.internal_deposit(account_id, amount);
+        emit_ft_mint(account_id, amount, Some("conversion rolled back"));
+        amount
+    }
+}
@@ -0,0 +1,118 @@
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::{
+    assert_one_yocto, env, log, near_bindgen, serde_json, AccountId, Promise, PromiseResult,
+};
+
+use crate::external::{ext_ft, ext_self, GAS_FOR_FT_METADATA, GAS_FOR_RESOLVE_RATIO_DECIMALS};
+use crate::native::{is_native_near, NEAR_DECIMALS};
+use crate::{Contract, ContractContract, SetStatus};
+
+#[near_bindgen]
+impl Contract {
+    /// Normalize the ratios with the decimals returned by `ft_metadata` of every underlying
+    /// token, in the order of the ratios. If any of the calls failed, the set is marked as failed
+    #[private]
+    pub fn resolve_ratio_decimals(&mut self) {
+        assert_eq!(
+            self.set_info.status,
+            SetStatus::PendingMetadata,
+            "Expected the set to be waiting on the underlying metadata"
+        );
+        let token_ids = self.fetched_token_ids();
+        let mut fetched_decimals = vec![];
+        for i in 0..env::promise_results_count() {
+            let metadata = match env::promise_result(i) {
+                PromiseResult::NotReady => unreachable!(),
+                PromiseResult::Successful(value) => {
+                    serde_json::from_slice::<FungibleTokenMetadata>(&value).ok()
+                }
+                PromiseResult::Failed => None,
+            };
+            match metadata {
+                Some(metadata) => fetched_decimals.push(metadata.decimals),
+                None => {
+                    let token_id = token_ids.get(i as usize).map(String::as_str).unwrap_or("?");
+                    log!("Failed to fetch the metadata of {}", token_id);
+                    self.set_info.status = SetStatus::Failed;
+                    return;
+                }
+            }
+        }
+        self.apply_ratio_decimals(fetched_decimals);
+    }
+
+    /// Fetch the metadata of the underlying tokens again after a failed attempt. Can be
+    /// called by anyone
+    #[payable]
+    pub fn retry_ratio_decimals(&mut self) {
+        assert_one_yocto();
+        assert_eq!(
+            self.set_info.status,
+            SetStatus::Failed,
+            "Expected the metadata fetch of the set to have failed"
+        );
+        self.set_info.status = SetStatus::PendingMetadata;
+        self.fetch_ratio_decimals();
+    }
+}
+
+impl Contract {
+    /// Fetch the metadata of every underlying token and normalize the ratios in the callback.
+    /// The decimals of native NEAR are known, so a set of only native NEAR is normalized
+    /// right away
+    pub(crate) fn fetch_ratio_decimals(&mut self) {
+        let mut promise: Option<Promise> = None;
+        for token_id in self.fetched_token_ids() {
+            let metadata = ext_ft::ft_metadata(&token_id, 0, GAS_FOR_FT_METADATA);
+            promise = Some(match promise {
+                Some(promise) => promise.and(metadata),
+                None => metadata,
+            });
+        }
+        match promise {
+            Some(promise) => {
+                promise.then(ext_self::resolve_ratio_decimals(
+                    &env::current_account_id(),
+                    0,
+                    GAS_FOR_RESOLVE_RATIO_DECIMALS,
+                ));
+            }
+            None => self.apply_ratio_decimals(vec![]),
+        }
+    }
+
+    /// The underlying tokens whose metadata has to be fetched, in the order of the ratios
+    fn fetched_token_ids(&self) -> Vec<AccountId> {
+        self.set_info
+            .ratios
+            .iter()
+            .map(|ratio| ratio.token_id)
+            .filter(|token_id| !is_native_near(token_id))
+            .collect()
+    }
+
+    /// Normalize the ratios with the fetched decimals, filling in those of native NEAR
+    fn apply_ratio_decimals(&mut self, fetched_decimals: Vec<u8>) {
+        let mut fetched_decimals = fetched_decimals.into_iter();
+        let token_decimals = self
+            .set_info
+            .ratios
+            .iter()
+            .map(|ratio| {
+                if is_native_near(&ratio.token_id) {
+                    NEAR_DECIMALS
+                } else {
+                    fetched_decimals.next().expect("Expected the decimals of every token")
+                }
+            })
+            .collect();
+
+        match self.set_info.normalize_ratios(token_decimals) {
+            Ok(()) => self.set_info.status = SetStatus::Ready,
+            Err(e) => {
+                log!("Failed to normalize the set ratios: {}", e);
+                self.set_info.status = SetStatus::Failed;
+            }
+        }
+    }
+}
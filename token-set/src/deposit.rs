@@ -0,0 +1,119 @@
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, serde_json, AccountId, Balance, PromiseOrValue};
+
+use crate::{Contract, ContractContract};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+/// An action to take after the deposited tokens are credited to the internal balance
+pub enum DepositAction {
+    /// Wrap the internal balances as soon as they complete a basket
+    Wrap,
+    /// Bid the deposited tokens in the running auction, refunding what is not used
+    Bid,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(crate = "near_sdk::serde")]
+/// The `msg` accepted by `ft_transfer_call` when depositing into the token set.
+/// An empty `msg` credits the sender. It extends the `{"sender_id": ...}` message of the
+/// internal balance plugin, so plugin deposits keep working
+///
+/// For example, `{"action":"wrap","amount":"1000000","receiver_id":"alice.near"}`
+/// or `{"action":"bid","min_amount_out":"100"}`
+pub struct DepositMsg {
+    /// The account to credit the deposit to, defaults to the sender
+    pub sender_id: Option<ValidAccountId>,
+    /// Only the sender can act on its own deposit, so an action requires `sender_id` to be
+    /// unset or the sender itself
+    pub action: Option<DepositAction>,
+    /// The amount of set tokens to wrap, defaults to the maximum wrappable amount
+    pub amount: Option<U128>,
+    /// The account receiving the wrapped set tokens or the auctioned token, defaults to the
+    /// sender
+    pub receiver_id: Option<ValidAccountId>,
+    /// The minimum amount of the auctioned token to receive for a bid
+    pub min_amount_out: Option<U128>,
+}
+
+// This replaces the receiver of the internal balance plugin, which only credits the deposit
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Credit the transferred tokens to the internal balance of the sender (or `msg.sender_id`)
+    /// and run the action in `msg`, if any. All the transferred tokens are used, except for
+    /// the part of a bid which is not filled, and the transfer is refunded while deposits are
+    /// paused
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.paused.assert_deposit_active();
+        let token_id = env::predecessor_account_id();
+        let msg: DepositMsg = if msg.is_empty() {
+            DepositMsg::default()
+        } else {
+            serde_json::from_str(&msg).expect("Expected the msg to be a valid deposit message")
+        };
+        let sender_id: AccountId = sender_id.into();
+        let account_id: AccountId =
+            msg.sender_id.clone().map(|s| s.into()).unwrap_or(sender_id.clone());
+        if msg.action.is_some() && account_id != sender_id {
+            panic!(
+                "Expected the deposit of @{} to be credited to itself to run an action",
+                sender_id
+            );
+        }
+
+        let unused = self.on_deposit(&account_id, &token_id, amount.0, msg);
+        PromiseOrValue::Value(U128(unused))
+    }
+}
+
+impl Contract {
+    /// return the amount of the deposit which was not used
+    pub(crate) fn on_deposit(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+        msg: DepositMsg,
+    ) -> Balance {
+        // A bid is paid by the deposit itself and is not credited to the internal balance
+        if let Some(DepositAction::Bid) = msg.action {
+            let receiver_id: AccountId =
+                msg.receiver_id.map(|r| r.into()).unwrap_or_else(|| account_id.clone());
+            let min_amount_out = msg.min_amount_out.map(|a| a.0);
+            return self.on_bid(account_id, &receiver_id, token_id, amount, min_amount_out);
+        }
+        self.increase_balance(account_id, token_id, amount);
+
+        match msg.action {
+            Some(DepositAction::Wrap) => {
+                let receiver_id: AccountId =
+                    msg.receiver_id.map(|r| r.into()).unwrap_or_else(|| account_id.clone());
+                let max_amount = self.get_max_amount(&self.accrued_pricing(), account_id);
+                let amount_wrap = msg.amount.map(|a| a.0).unwrap_or(max_amount);
+                // The basket is not complete yet, so leave the deposit in the internal balance
+                if amount_wrap == 0 || amount_wrap > max_amount {
+                    log!(
+                        "Not wrapping for @{}, can wrap {} out of the requested {}",
+                        account_id,
+                        max_amount,
+                        amount_wrap
+                    );
+                    return 0;
+                }
+                let owner_id = self.owner_id.clone();
+                self.wrap_internal(&owner_id, account_id, &receiver_id, Some(amount_wrap), false);
+            }
+            Some(DepositAction::Bid) | None => {}
+        }
+        0
+    }
+}
@@ -0,0 +1,136 @@
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId, Balance};
+
+use crate::preview::to_token_amounts;
+use crate::utils::timestamp_after;
+use crate::{Contract, ContractContract, TokenAmount};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+/// The underlying tokens released from the set balance of a closed account
+pub struct Escrow {
+    pub amounts: Vec<TokenAmount>,
+    /// The block timestamp, in nanoseconds, until which the closed account can claim the
+    /// tokens. Afterwards they go to the platform
+    pub claimable_until: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountEscrow {
+    pub account_id: AccountId,
+    pub escrow: Escrow,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Claim the tokens escrowed when the caller's account was closed into the caller's
+    /// internal balance. The caller has to register for internal balances again first
+    #[payable]
+    pub fn claim_escrow(&mut self) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.assert_internal_account_registered(&account_id);
+        let escrow =
+            self.escrows.remove(&account_id).unwrap_or_else(|| panic!("There is no escrow"));
+        if env::block_timestamp() > escrow.claimable_until.0 {
+            panic!("The escrow could only be claimed until {}", escrow.claimable_until.0);
+        }
+        self.credit_escrow(&account_id, escrow);
+        log!("@{} claimed its escrow", account_id);
+    }
+
+    /// Give the escrow of `account_id` to the platform once it can no longer be claimed.
+    /// Can be called by anyone
+    pub fn release_expired_escrow(&mut self, account_id: ValidAccountId) {
+        let escrow = self
+            .escrows
+            .remove(account_id.as_ref())
+            .unwrap_or_else(|| panic!("There is no escrow"));
+        if env::block_timestamp() <= escrow.claimable_until.0 {
+            panic!("The escrow can still be claimed until {}", escrow.claimable_until.0);
+        }
+        let platform_id = self.set_info.fee.platform_id.clone();
+        self.assert_internal_account_registered(&platform_id);
+        self.credit_escrow(&platform_id, escrow);
+        log!("Released the expired escrow of @{} to @{}", account_id.as_ref(), platform_id);
+    }
+
+    pub fn get_escrow(&self, account_id: ValidAccountId) -> Option<Escrow> {
+        self.escrows.get(account_id.as_ref())
+    }
+
+    /// The unclaimed escrows, paginated
+    pub fn get_escrows(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<AccountEscrow> {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.escrows.len());
+        self.escrows
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(account_id, escrow)| AccountEscrow { account_id, escrow })
+            .collect()
+    }
+}
+
+impl Contract {
+    /// Burn `amount` set tokens of the closed account `account_id` and escrow the underlying
+    /// tokens for it. Only whole units of the set are released, the backing of the rest stays
+    /// locked
+    pub(crate) fn escrow_closed_account(&mut self, account_id: &AccountId, amount: Balance) {
+        let units = amount / self.set_info.wrap_to_underlying_ratio;
+        if units == 0 {
+            return;
+        }
+        let released = self.release_burned_units(units);
+
+        let mut amounts = to_token_amounts(released);
+        for TokenAmount { token_id, amount } in amounts.iter() {
+            self.update_escrow_total(token_id, amount.0, true);
+        }
+        // Merge with the escrow of an earlier closing of the same account
+        if let Some(previous) = self.escrows.get(account_id) {
+            for previous_amount in previous.amounts {
+                match amounts.iter_mut().find(|a| a.token_id == previous_amount.token_id) {
+                    Some(a) => a.amount = (a.amount.0 + previous_amount.amount.0).into(),
+                    None => amounts.push(previous_amount),
+                }
+            }
+        }
+        let escrow =
+            Escrow { amounts, claimable_until: timestamp_after(self.escrow_window).into() };
+        log!("Escrowed the tokens of @{} until {}", account_id, escrow.claimable_until.0);
+        self.escrows.insert(account_id, &escrow);
+    }
+
+    fn credit_escrow(&mut self, account_id: &AccountId, escrow: Escrow) {
+        for TokenAmount { token_id, amount } in escrow.amounts {
+            self.update_escrow_total(&token_id, amount.0, false);
+            self.increase_balance(account_id, &token_id, amount.0);
+        }
+    }
+
+    fn update_escrow_total(&mut self, token_id: &AccountId, amount: Balance, add: bool) {
+        let current = self.escrow_total(token_id);
+        let total = if add {
+            current + amount
+        } else {
+            current
+                .checked_sub(amount)
+                .unwrap_or_else(|| panic!("Escrow total underflow for {}", token_id))
+        };
+        if total == 0 {
+            self.escrow_totals.remove(token_id);
+        } else {
+            self.escrow_totals.insert(token_id, &total);
+        }
+    }
+
+    /// The total escrowed amount of `token_id` over all closed accounts
+    pub(crate) fn escrow_total(&self, token_id: &AccountId) -> Balance {
+        self.escrow_totals.get(token_id).unwrap_or(0)
+    }
+}
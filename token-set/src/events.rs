@@ -0,0 +1,325 @@
+//! NEP-297 events, logged as `EVENT_JSON:{"standard":..,"version":..,"event":..,"data":..}`.
+//! The set token emits the NEP-141 `ft_mint`, `ft_burn` and `ft_transfer` events, and the
+//! `token_set` events below for wrapping, unwrapping and fees. The NEP-141 events of a
+//! `ft_transfer_call` add up to the net amount: the refund is a reverse `ft_transfer`, or an
+//! `ft_burn` from the receiver if the sender closed its account in the meantime. The sets
+//! hosted by the contract emit the NEP-245 `mt_mint`, `mt_burn` and `mt_transfer` events
+use near_sdk::json_types::{Base64VecU8, U128};
+use near_sdk::serde::Serialize;
+use near_sdk::serde_json::json;
+use near_sdk::{log, AccountId, Balance};
+
+use crate::preview::to_token_amounts;
+use crate::{FeeReceiver, TokenAmount};
+
+const NEP141_STANDARD: &str = "nep141";
+const NEP141_VERSION: &str = "1.0.0";
+const NEP245_STANDARD: &str = "nep245";
+const NEP245_VERSION: &str = "1.0.0";
+const SET_STANDARD: &str = "token_set";
+const SET_VERSION: &str = "1.0.0";
+
+fn emit<T: Serialize>(standard: &str, version: &str, event: &str, data: T) {
+    let event = json!({
+        "standard": standard,
+        "version": version,
+        "event": event,
+        "data": data,
+    });
+    log!("EVENT_JSON:{}", event.to_string());
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct FtMintOrBurn<'a> {
+    owner_id: &'a AccountId,
+    amount: U128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memo: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct FtTransfer<'a> {
+    old_owner_id: &'a AccountId,
+    new_owner_id: &'a AccountId,
+    amount: U128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memo: Option<&'a str>,
+}
+
+pub(crate) fn emit_ft_mint(owner_id: &AccountId, amount: Balance, memo: Option<&str>) {
+    if amount == 0 {
+        return;
+    }
+    emit(
+        NEP141_STANDARD,
+        NEP141_VERSION,
+        "ft_mint",
+        [FtMintOrBurn { owner_id, amount: amount.into(), memo }],
+    );
+}
+
+pub(crate) fn emit_ft_burn(owner_id: &AccountId, amount: Balance, memo: Option<&str>) {
+    if amount == 0 {
+        return;
+    }
+    emit(
+        NEP141_STANDARD,
+        NEP141_VERSION,
+        "ft_burn",
+        [FtMintOrBurn { owner_id, amount: amount.into(), memo }],
+    );
+}
+
+pub(crate) fn emit_ft_transfer(
+    old_owner_id: &AccountId,
+    new_owner_id: &AccountId,
+    amount: Balance,
+    memo: Option<&str>,
+) {
+    if amount == 0 {
+        return;
+    }
+    emit(
+        NEP141_STANDARD,
+        NEP141_VERSION,
+        "ft_transfer",
+        [FtTransfer { old_owner_id, new_owner_id, amount: amount.into(), memo }],
+    );
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct MtMintOrBurn<'a> {
+    owner_id: &'a AccountId,
+    token_ids: [&'a str; 1],
+    amounts: [U128; 1],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memo: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct MtTransfer<'a> {
+    old_owner_id: &'a AccountId,
+    new_owner_id: &'a AccountId,
+    token_ids: &'a [String],
+    amounts: &'a [U128],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memo: Option<&'a str>,
+}
+
+pub(crate) fn emit_mt_mint(
+    owner_id: &AccountId,
+    token_id: &str,
+    amount: Balance,
+    memo: Option<&str>,
+) {
+    if amount == 0 {
+        return;
+    }
+    emit(
+        NEP245_STANDARD,
+        NEP245_VERSION,
+        "mt_mint",
+        [MtMintOrBurn { owner_id, token_ids: [token_id], amounts: [amount.into()], memo }],
+    );
+}
+
+pub(crate) fn emit_mt_burn(
+    owner_id: &AccountId,
+    token_id: &str,
+    amount: Balance,
+    memo: Option<&str>,
+) {
+    if amount == 0 {
+        return;
+    }
+    emit(
+        NEP245_STANDARD,
+        NEP245_VERSION,
+        "mt_burn",
+        [MtMintOrBurn { owner_id, token_ids: [token_id], amounts: [amount.into()], memo }],
+    );
+}
+
+pub(crate) fn emit_mt_transfer(
+    old_owner_id: &AccountId,
+    new_owner_id: &AccountId,
+    token_ids: &[String],
+    amounts: &[U128],
+    memo: Option<&str>,
+) {
+    emit(
+        NEP245_STANDARD,
+        NEP245_VERSION,
+        "mt_transfer",
+        [MtTransfer { old_owner_id, new_owner_id, token_ids, amounts, memo }],
+    );
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct SetWrap<'a> {
+    account_id: &'a AccountId,
+    receiver_id: &'a AccountId,
+    /// The amount of set tokens minted, including the fees
+    amount: U128,
+    /// The underlying tokens locked
+    underlying: Vec<TokenAmount>,
+}
+
+pub(crate) fn emit_set_wrap(
+    account_id: &AccountId,
+    receiver_id: &AccountId,
+    amount: Balance,
+    underlying: Vec<TokenAmount>,
+) {
+    emit(
+        SET_STANDARD,
+        SET_VERSION,
+        "set_wrap",
+        SetWrap { account_id, receiver_id, amount: amount.into(), underlying },
+    );
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct SetUnwrap<'a> {
+    account_id: &'a AccountId,
+    /// The amount of set tokens redeemed, including the fees
+    amount: U128,
+    /// The amount of set tokens burned
+    burned: U128,
+    /// The underlying tokens released to the redeemer
+    underlying: Vec<TokenAmount>,
+}
+
+pub(crate) fn emit_set_unwrap(
+    account_id: &AccountId,
+    amount: Balance,
+    burned: Balance,
+    underlying: Vec<TokenAmount>,
+) {
+    emit(
+        SET_STANDARD,
+        SET_VERSION,
+        "set_unwrap",
+        SetUnwrap { account_id, amount: amount.into(), burned: burned.into(), underlying },
+    );
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FeeKind {
+    Mint,
+    Redeem,
+    Streaming,
+    FlashLoan,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct FeeMinted<'a> {
+    kind: FeeKind,
+    owner_id: &'a AccountId,
+    owner_amount: U128,
+    platform_id: &'a AccountId,
+    platform_amount: U128,
+}
+
+/// Emitted for fees paid in newly minted set tokens
+pub(crate) fn emit_fee_minted(
+    kind: FeeKind,
+    owner_id: &AccountId,
+    owner_amount: Balance,
+    platform_id: &AccountId,
+    platform_amount: Balance,
+) {
+    if owner_amount == 0 && platform_amount == 0 {
+        return;
+    }
+    emit(
+        SET_STANDARD,
+        SET_VERSION,
+        "fee_minted",
+        FeeMinted {
+            kind,
+            owner_id,
+            owner_amount: owner_amount.into(),
+            platform_id,
+            platform_amount: platform_amount.into(),
+        },
+    );
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct FeeCharged<'a> {
+    kind: FeeKind,
+    owner_id: &'a AccountId,
+    /// The set tokens transferred to the owner
+    owner_amount: U128,
+    /// The underlying tokens credited to the internal balance of the owner
+    owner_components: Vec<TokenAmount>,
+    platform_id: &'a AccountId,
+    platform_amount: U128,
+    platform_components: Vec<TokenAmount>,
+}
+
+/// Emitted for fees which are not minted: set tokens transferred from the redeemer and
+/// underlying tokens credited to the internal balances of the owner and the platform
+pub(crate) fn emit_fee_charged(
+    kind: FeeKind,
+    owner_id: &AccountId,
+    owner_amount: Balance,
+    owner_components: Vec<(AccountId, Balance)>,
+    platform_id: &AccountId,
+    platform_amount: Balance,
+    platform_components: Vec<(AccountId, Balance)>,
+) {
+    let non_zero = |components: Vec<(AccountId, Balance)>| {
+        to_token_amounts(components.into_iter().filter(|(_, amount)| *amount > 0).collect())
+    };
+    let owner_components = non_zero(owner_components);
+    let platform_components = non_zero(platform_components);
+    if owner_amount == 0
+        && platform_amount == 0
+        && owner_components.is_empty()
+        && platform_components.is_empty()
+    {
+        return;
+    }
+    emit(
+        SET_STANDARD,
+        SET_VERSION,
+        "fee_charged",
+        FeeCharged {
+            kind,
+            owner_id,
+            owner_amount: owner_amount.into(),
+            owner_components,
+            platform_id,
+            platform_amount: platform_amount.into(),
+            platform_components,
+        },
+    );
+}
+
+pub(crate) fn emit_fee_updated(fee: &FeeReceiver) {
+    emit(SET_STANDARD, SET_VERSION, "fee_updated", json!({ "fee": fee }));
+}
+
+pub(crate) fn emit_metadata_updated(
+    reference: &Option<String>,
+    reference_hash: &Option<Base64VecU8>,
+) {
+    emit(
+        SET_STANDARD,
+        SET_VERSION,
+        "metadata_updated",
+        json!({ "reference": reference, "reference_hash": reference_hash }),
+    );
+}
@@ -0,0 +1,131 @@
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::json_types::U128;
+use near_sdk::{ext_contract, AccountId, Gas, PromiseOrValue};
+
+use crate::{SetMetadata, TokenAmount, TokenReconciliation};
+
+pub(crate) const BASE_GAS: Gas = 5_000_000_000_000;
+pub(crate) const GAS_FOR_FT_TRANSFER: Gas = BASE_GAS * 2;
+pub(crate) const GAS_FOR_FT_TRANSFER_CALL: Gas = BASE_GAS * 7;
+pub(crate) const GAS_FOR_RESOLVE_TRANSFER: Gas = BASE_GAS * 2;
+pub(crate) const GAS_FOR_FT_METADATA: Gas = BASE_GAS;
+pub(crate) const GAS_FOR_RESOLVE_RATIO_DECIMALS: Gas = BASE_GAS * 3;
+pub(crate) const GAS_FOR_FT_BALANCE_OF: Gas = BASE_GAS;
+pub(crate) const GAS_FOR_RESOLVE_RECONCILE: Gas = BASE_GAS * 10;
+pub(crate) const GAS_FOR_RESOLVE_FLASH_LOAN: Gas = BASE_GAS * 4;
+pub(crate) const GAS_FOR_FLASH_LOAN: Gas = GAS_FOR_RESOLVE_FLASH_LOAN + BASE_GAS * 3;
+pub(crate) const GAS_FOR_RESOLVE_FLASH_MINT: Gas = BASE_GAS * 4;
+pub(crate) const GAS_FOR_FLASH_MINT: Gas = GAS_FOR_RESOLVE_FLASH_MINT + BASE_GAS * 3;
+pub(crate) const GAS_FOR_SET_METADATA: Gas = BASE_GAS;
+pub(crate) const GAS_FOR_CONVERT_TO: Gas = GAS_FOR_SET_METADATA + BASE_GAS * 2;
+pub(crate) const GAS_FOR_WRAP_TO: Gas = BASE_GAS * 4;
+pub(crate) const GAS_FOR_WITHDRAW_TO: Gas = BASE_GAS * 6;
+pub(crate) const GAS_FOR_RESOLVE_CONVERT_ROLLBACK: Gas = BASE_GAS * 6;
+pub(crate) const GAS_FOR_RESOLVE_MT_TRANSFER: Gas = BASE_GAS * 3;
+pub(crate) const GAS_FOR_MT_TRANSFER_CALL: Gas = GAS_FOR_RESOLVE_MT_TRANSFER + BASE_GAS * 4;
+
+#[ext_contract(ext_ft)]
+pub trait ExtFungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+}
+
+#[ext_contract(ext_flash_mint_receiver)]
+pub trait FlashMintReceiver {
+    /// Called with `amount` set tokens minted to the receiver, which has to hold `amount` plus
+    /// `fee` set tokens once it returns
+    fn on_flash_mint(&mut self, sender_id: AccountId, amount: U128, fee: U128, msg: String);
+}
+
+/// The NEP-245 receiver of the tokens of the hosted sets
+#[ext_contract(ext_mt_receiver)]
+pub trait MultiTokenReceiver {
+    fn mt_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_ids: Vec<AccountId>,
+        token_ids: Vec<String>,
+        amounts: Vec<U128>,
+        msg: String,
+    ) -> PromiseOrValue<Vec<U128>>;
+}
+
+#[ext_contract(ext_set)]
+pub trait ExtTokenSet {
+    fn set_metadata(&self) -> SetMetadata;
+    fn wrap_to(
+        &mut self,
+        receiver_id: AccountId,
+        amount: Option<U128>,
+        round_down: Option<bool>,
+    ) -> U128;
+}
+
+/// The internal balances of the plugin on another contract
+#[ext_contract(ext_internal_balances)]
+pub trait ExtInternalBalances {
+    fn withdraw_to(
+        &mut self,
+        amount: U128,
+        token_id: AccountId,
+        recipient: Option<AccountId>,
+        msg: Option<String>,
+    );
+}
+
+#[ext_contract(ext_self)]
+pub trait ExtSelf {
+    fn resolve_unwrap_withdraw(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        is_call: bool,
+    ) -> U128;
+    fn resolve_ratio_decimals(&mut self);
+    fn resolve_reconcile(
+        &mut self,
+        token_ids: Vec<AccountId>,
+        auto_pause: bool,
+    ) -> Vec<TokenReconciliation>;
+    fn resolve_flash_loan(&mut self, fee: U128) -> bool;
+    fn resolve_flash_mint(&mut self) -> bool;
+    fn mt_resolve_transfer(
+        &mut self,
+        previous_owner_ids: Vec<AccountId>,
+        receiver_id: AccountId,
+        token_ids: Vec<String>,
+        amounts: Vec<U128>,
+    ) -> Vec<U128>;
+    fn resolve_convert_to(&mut self, account_id: AccountId, target_set: AccountId, amount: U128);
+    fn resolve_convert_deposits(
+        &mut self,
+        account_id: AccountId,
+        target_set: AccountId,
+        deposits: Vec<TokenAmount>,
+        amount_target: U128,
+        burned: U128,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_convert_wrap(
+        &mut self,
+        account_id: AccountId,
+        target_set: AccountId,
+        deposits: Vec<TokenAmount>,
+        burned: U128,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_convert_rollback(
+        &mut self,
+        account_id: AccountId,
+        target_set: AccountId,
+        withdrawals: Vec<TokenAmount>,
+        burned: U128,
+    ) -> U128;
+}
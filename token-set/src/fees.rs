@@ -0,0 +1,410 @@
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId, Balance};
+
+use crate::events::{
+    emit_fee_charged, emit_fee_minted, emit_fee_updated, emit_ft_burn, emit_ft_mint,
+    emit_ft_transfer, emit_set_unwrap, FeeKind,
+};
+use crate::preview::to_token_amounts;
+use crate::token_set_info::{scale_ratio, Pricing, FEE_DENOMINATOR, NANOS_PER_SECOND};
+use crate::utils::{mul_div, timestamp_after, U256};
+use crate::{Contract, ContractContract, FeeReceiver};
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+/// The streaming fees accrued since the last accrual which are not minted yet
+pub struct AccruedFees {
+    /// The amount of set tokens to be minted to the owner
+    pub owner_fee_amount: U128,
+    /// The amount of set tokens to be minted to the platform
+    pub platform_fee_amount: U128,
+    /// The number of whole seconds the fees were accrued over
+    pub seconds: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+/// A change of the owner's fees which can be applied once the fee change delay has passed
+pub struct PendingFeeChange {
+    pub owner_fee: U128,
+    pub owner_redeem_fee: U128,
+    pub owner_streaming_fee: U128,
+    /// The block timestamp, in nanoseconds, from which the change can be applied
+    pub applicable_at: U64,
+}
+
+impl PendingFeeChange {
+    fn apply_to(&self, fee: &mut FeeReceiver) {
+        fee.owner_fee = self.owner_fee.0;
+        fee.owner_redeem_fee = self.owner_redeem_fee.0;
+        fee.owner_streaming_fee = self.owner_streaming_fee.0;
+    }
+}
+
+impl FeeReceiver {
+    /// Check that every fee and the sum of the owner and platform fees fit under the fee
+    /// denominator, and that the platform fee is within its maximum
+    pub(crate) fn assert_valid(&self) {
+        for (owner_fee, platform_fee) in
+            [(self.owner_fee, self.platform_fee), (self.owner_redeem_fee, self.platform_redeem_fee)]
+                .iter()
+        {
+            if *owner_fee > FEE_DENOMINATOR || *platform_fee > FEE_DENOMINATOR {
+                panic!(
+                    "Expected the fees to be less than the fee denominator of {}",
+                    FEE_DENOMINATOR
+                );
+            }
+            if owner_fee + platform_fee > FEE_DENOMINATOR {
+                panic!(
+                    "Expected the sum of fees to be less than the fee denominator of {}",
+                    FEE_DENOMINATOR
+                );
+            }
+        }
+        if self.platform_fee > self.max_platform_fee {
+            panic!("Expected the platform fee to be at most {}", self.max_platform_fee);
+        }
+    }
+
+    /// Split a minted amount into the amounts for the minter, the owner and the platform
+    pub(crate) fn mint_fee_split(&self, amount: Balance) -> (Balance, Balance, Balance) {
+        let owner_incr = (U256::from(amount) * U256::from(self.owner_fee)
+            / U256::from(FEE_DENOMINATOR))
+        .as_u128();
+        let platform_incr = (U256::from(amount) * U256::from(self.platform_fee)
+            / U256::from(FEE_DENOMINATOR))
+        .as_u128();
+        (amount - owner_incr - platform_incr, owner_incr, platform_incr)
+    }
+}
+
+/// How redeeming set tokens is split between the redeemer, the owner and the platform
+pub(crate) struct Redemption {
+    /// The amount of set tokens burned
+    pub burned: Balance,
+    /// The amount of set tokens which cannot be burned and goes back to the redeemer
+    pub remainder: Balance,
+    /// The fee paid to the owner in set tokens
+    pub owner_fee: Balance,
+    /// The fee paid to the platform in set tokens
+    pub platform_fee: Balance,
+    /// The underlying tokens released to the redeemer
+    pub released: Vec<(AccountId, Balance)>,
+    /// The underlying tokens paid to the owner as a fee
+    pub owner_fee_underlying: Vec<(AccountId, Balance)>,
+    /// The underlying tokens paid to the platform as a fee
+    pub platform_fee_underlying: Vec<(AccountId, Balance)>,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Mint the streaming fees accrued since the last accrual to the owner and the platform
+    pub fn accrue_fees(&mut self) {
+        self.accrue_streaming_fees();
+    }
+
+    /// Propose changing the owner's fees, leaving the fees which are not given unchanged.
+    /// No fee can increase by more than the maximum fee increase
+    #[payable]
+    pub fn propose_fee_change(
+        &mut self,
+        owner_fee: Option<U128>,
+        owner_redeem_fee: Option<U128>,
+        owner_streaming_fee: Option<U128>,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.propose_owner_fees(
+            owner_fee.map(|f| f.0),
+            owner_redeem_fee.map(|f| f.0),
+            owner_streaming_fee.map(|f| f.0),
+        );
+    }
+
+    /// Apply the pending fee change once its delay has passed. Can be called by anyone
+    pub fn apply_fee_change(&mut self) {
+        let change = self
+            .set_info
+            .pending_fee_change
+            .take()
+            .unwrap_or_else(|| panic!("There is no pending fee change"));
+        if env::block_timestamp() < change.applicable_at.0 {
+            panic!("The fee change can only be applied from {}", change.applicable_at.0);
+        }
+        // Charge the elapsed time at the old streaming fee
+        self.accrue_streaming_fees();
+        change.apply_to(&mut self.set_info.fee);
+        self.set_info.fee.assert_valid();
+        emit_fee_updated(&self.set_info.fee);
+        log!(
+            "Applied the fee change to an owner fee of {}, redeem fee of {} and streaming fee of {}",
+            change.owner_fee.0,
+            change.owner_redeem_fee.0,
+            change.owner_streaming_fee.0
+        );
+    }
+
+    #[payable]
+    pub fn cancel_fee_change(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        if self.set_info.pending_fee_change.take().is_none() {
+            panic!("There is no pending fee change");
+        }
+        log!("Cancelled the pending fee change");
+    }
+
+    pub fn pending_fee_change(&self) -> Option<PendingFeeChange> {
+        self.set_info.pending_fee_change.clone()
+    }
+
+    /// The streaming fees accrued since the last accrual which are not minted yet
+    pub fn accrued_fees(&self) -> AccruedFees {
+        let (owner_fee_amount, platform_fee_amount, seconds) = self.streaming_fee_split();
+        AccruedFees {
+            owner_fee_amount: owner_fee_amount.into(),
+            platform_fee_amount: platform_fee_amount.into(),
+            seconds,
+        }
+    }
+}
+
+impl Contract {
+    /// Queue a change of the owner's fees, replacing any pending change
+    pub(crate) fn propose_owner_fees(
+        &mut self,
+        owner_fee: Option<u128>,
+        owner_redeem_fee: Option<u128>,
+        owner_streaming_fee: Option<u128>,
+    ) {
+        let fee = &self.set_info.fee;
+        if !fee.updatable {
+            panic!("Cannot update a token set fee unless the fee property is marked initially updatable")
+        }
+        let change = PendingFeeChange {
+            owner_fee: owner_fee.unwrap_or(fee.owner_fee).into(),
+            owner_redeem_fee: owner_redeem_fee.unwrap_or(fee.owner_redeem_fee).into(),
+            owner_streaming_fee: owner_streaming_fee.unwrap_or(fee.owner_streaming_fee).into(),
+            applicable_at: timestamp_after(self.set_info.fee_change_delay).into(),
+        };
+        for (current, new) in [
+            (fee.owner_fee, change.owner_fee.0),
+            (fee.owner_redeem_fee, change.owner_redeem_fee.0),
+            (fee.owner_streaming_fee, change.owner_streaming_fee.0),
+        ]
+        .iter()
+        {
+            if new > current && new - current > self.set_info.max_fee_increase {
+                panic!(
+                    "Expected a fee to increase by at most {}, got an increase of {}",
+                    self.set_info.max_fee_increase,
+                    new - current
+                );
+            }
+        }
+        let mut new_fee = fee.clone();
+        change.apply_to(&mut new_fee);
+        new_fee.assert_valid();
+
+        log!("Proposed a fee change applicable from {}", change.applicable_at.0);
+        self.set_info.pending_fee_change = Some(change);
+    }
+
+    /// The streaming fees for the owner and platform accrued on the total supply since the last
+    /// accrual, and the number of whole seconds they were accrued over.
+    /// The fraction of the supply charged is capped at the fee denominator
+    fn streaming_fee_split(&self) -> (Balance, Balance, u64) {
+        let seconds = env::block_timestamp().saturating_sub(self.set_info.last_fee_accrual)
+            / NANOS_PER_SECOND;
+        let fee = &self.set_info.fee;
+        let owner_fraction =
+            fee.owner_streaming_fee.saturating_mul(seconds as u128).min(FEE_DENOMINATOR);
+        let platform_fraction = fee
+            .platform_streaming_fee
+            .saturating_mul(seconds as u128)
+            .min(FEE_DENOMINATOR - owner_fraction);
+        let supply = self.token.total_supply;
+        (
+            mul_div(supply, owner_fraction, FEE_DENOMINATOR, false).unwrap(),
+            mul_div(supply, platform_fraction, FEE_DENOMINATOR, false).unwrap(),
+            seconds,
+        )
+    }
+
+    /// The pricing with the pending streaming fees accrued, as `accrue_streaming_fees` would
+    /// leave it
+    pub(crate) fn accrued_pricing(&self) -> Pricing {
+        let (owner_amount, platform_amount, _) = self.streaming_fee_split();
+        let minted = owner_amount + platform_amount;
+        let mut pricing = self.pricing();
+        if minted > 0 {
+            let supply = pricing.total_supply;
+            pricing.ratios =
+                pricing.ratios.iter().map(|r| scale_ratio(r, supply, supply + minted)).collect();
+            pricing.total_supply += minted;
+        }
+        pricing
+    }
+
+    /// Mint the accrued streaming fees and scale down the ratios so that the new supply is
+    /// backed by the same underlying tokens
+    pub(crate) fn accrue_streaming_fees(&mut self) {
+        let (owner_amount, platform_amount, seconds) = self.streaming_fee_split();
+        let minted = owner_amount + platform_amount;
+        let supply = self.token.total_supply;
+        let no_fee = self.set_info.fee.owner_streaming_fee == 0
+            && self.set_info.fee.platform_streaming_fee == 0;
+        // Keep accruing if the fees are still too small to mint anything
+        if minted == 0 && supply > 0 && !no_fee {
+            return;
+        }
+        self.set_info.last_fee_accrual += seconds * NANOS_PER_SECOND;
+        if minted == 0 {
+            return;
+        }
+
+        self.set_info.scale_ratios(supply, supply + minted);
+        let owner_id = self.owner_id.clone();
+        let platform_id = self.set_info.fee.platform_id.clone();
+        self.token.internal_deposit(&owner_id, owner_amount);
+        self.token.internal_deposit(&platform_id, platform_amount);
+        emit_ft_mint(&owner_id, owner_amount, Some("streaming fee"));
+        emit_ft_mint(&platform_id, platform_amount, Some("streaming fee"));
+        emit_fee_minted(FeeKind::Streaming, &owner_id, owner_amount, &platform_id, platform_amount);
+    }
+
+    /// Split redeeming `amount` set tokens according to the redemption fee.
+    ///
+    /// If the fee is taken in set tokens, the fee is transferred to the owner and platform and
+    /// only the rest, rounded down to the wrap to underlying ratio, is burned.
+    /// Otherwise the fee is taken out of the released tokens. The owner or the platform takes
+    /// its fee in set tokens anyway while it is not registered for internal balances
+    pub(crate) fn redemption(&self, pricing: &Pricing, amount: Balance) -> Redemption {
+        let fee = &self.set_info.fee;
+        let owner_share = |amount: Balance| {
+            mul_div(amount, fee.owner_redeem_fee, FEE_DENOMINATOR, false).unwrap()
+        };
+        let platform_share = |amount: Balance| {
+            mul_div(amount, fee.platform_redeem_fee, FEE_DENOMINATOR, false).unwrap()
+        };
+        let in_components = |account_id: &AccountId| {
+            fee.redeem_fee_in_components && self.accounts.get_account(account_id).is_some()
+        };
+        let owner_in_components = in_components(&self.owner_id);
+        let platform_in_components = in_components(&fee.platform_id);
+
+        let owner_fee = if owner_in_components { 0 } else { owner_share(amount) };
+        let platform_fee = if platform_in_components { 0 } else { platform_share(amount) };
+        let net = amount - owner_fee - platform_fee;
+        let burned = self.round_to_wrap_ratio(net);
+        let underlying = self.underlying_amounts(pricing, burned);
+        let fee_underlying = |in_components: bool, share: &dyn Fn(Balance) -> Balance| {
+            if !in_components {
+                return vec![];
+            }
+            underlying.iter().map(|(t, a)| (t.clone(), share(*a))).collect::<Vec<_>>()
+        };
+        let owner_fee_underlying = fee_underlying(owner_in_components, &owner_share);
+        let platform_fee_underlying = fee_underlying(platform_in_components, &platform_share);
+        let fee_of = |fees: &Vec<(AccountId, Balance)>, i: usize| fees.get(i).map_or(0, |f| f.1);
+        let released = underlying
+            .iter()
+            .enumerate()
+            .map(|(i, (token_id, a))| {
+                let fees = fee_of(&owner_fee_underlying, i) + fee_of(&platform_fee_underlying, i);
+                (token_id.clone(), a - fees)
+            })
+            .collect();
+        Redemption {
+            burned,
+            remainder: net - burned,
+            owner_fee,
+            platform_fee,
+            released,
+            owner_fee_underlying,
+            platform_fee_underlying,
+        }
+    }
+
+    /// Take `amount` set tokens from `account_id`, pay the redemption fee and burn the rest
+    ///
+    /// return the underlying tokens released to the redeemer
+    pub(crate) fn redeem(
+        &mut self,
+        account_id: &AccountId,
+        amount: Balance,
+    ) -> Vec<(AccountId, Balance)> {
+        self.paused.assert_unwrap_active();
+        self.set_info.assert_no_flash_loan();
+        self.accrue_streaming_fees();
+        let pricing = self.pricing();
+        let redemption = self.redemption(&pricing, amount);
+        let owner_id = self.owner_id.clone();
+        let platform_id = self.set_info.fee.platform_id.clone();
+
+        let unlocked = self.underlying_amounts(&pricing, redemption.burned);
+        self.shrink_auction(
+            redemption.burned / self.set_info.wrap_to_underlying_ratio,
+            self.supply_units(),
+        );
+        self.token.internal_withdraw(account_id, amount);
+        for (token_id, unlocked) in unlocked {
+            self.release_backing(&token_id, unlocked);
+        }
+        if redemption.remainder > 0 {
+            self.token.internal_deposit(account_id, redemption.remainder);
+        }
+        if redemption.owner_fee > 0 {
+            self.token.internal_deposit(&owner_id, redemption.owner_fee);
+        }
+        if redemption.platform_fee > 0 {
+            self.token.internal_deposit(&platform_id, redemption.platform_fee);
+        }
+        self.credit_fee_underlying(&owner_id, redemption.owner_fee_underlying.clone());
+        self.credit_fee_underlying(&platform_id, redemption.platform_fee_underlying.clone());
+
+        emit_ft_burn(account_id, redemption.burned, None);
+        emit_ft_transfer(account_id, &owner_id, redemption.owner_fee, Some("redemption fee"));
+        emit_ft_transfer(account_id, &platform_id, redemption.platform_fee, Some("redemption fee"));
+        emit_fee_charged(
+            FeeKind::Redeem,
+            &owner_id,
+            redemption.owner_fee,
+            redemption.owner_fee_underlying,
+            &platform_id,
+            redemption.platform_fee,
+            redemption.platform_fee_underlying,
+        );
+        emit_set_unwrap(
+            account_id,
+            amount - redemption.remainder,
+            redemption.burned,
+            to_token_amounts(redemption.released.clone()),
+        );
+        redemption.released
+    }
+
+    /// Credit a fee in underlying tokens to the internal balance of `account_id`
+    ///
+    /// return false, crediting nothing, if `account_id` is not registered for internal balances
+    pub(crate) fn credit_fee_underlying(
+        &mut self,
+        account_id: &AccountId,
+        amounts: Vec<(AccountId, Balance)>,
+    ) -> bool {
+        if amounts.iter().all(|(_, amount)| *amount == 0) {
+            return true;
+        }
+        if self.accounts.get_account(account_id).is_none() {
+            return false;
+        }
+        for (token_id, amount) in amounts {
+            self.increase_balance(account_id, &token_id, amount);
+        }
+        true
+    }
+}
@@ -0,0 +1,404 @@
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, log, near_bindgen, serde_json, AccountId, Balance, Promise,
+    PromiseResult,
+};
+
+use crate::events::{emit_fee_charged, FeeKind};
+use crate::external::{ext_ft, ext_self, GAS_FOR_FLASH_LOAN, GAS_FOR_RESOLVE_FLASH_LOAN};
+use crate::native::is_native_near;
+use crate::token_set_info::FEE_DENOMINATOR;
+use crate::utils::mul_div;
+use crate::{Contract, ContractContract, SetInfo};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+/// An underlying token lent out by the set
+pub struct FlashLoan {
+    pub token_id: AccountId,
+    pub amount: U128,
+    /// The amount repaid with `repay_flash_loan` so far
+    pub repaid: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(crate = "near_sdk::serde")]
+/// The liquidity of one token its providers opted into flash loans
+pub struct FlashLoanPool {
+    /// The amount of the token in the pool, including the amount lent out
+    pub amount: U128,
+    /// The shares of all providers in the pool
+    pub shares: U128,
+    /// Bumped when a default takes the whole pool, which voids the shares of earlier rounds
+    pub round: u64,
+}
+
+impl SetInfo {
+    pub(crate) fn assert_no_flash_loan(&self) {
+        if self.flash_loan.is_some() {
+            panic!("An underlying token of the set is lent out");
+        }
+    }
+
+    /// The shares of `account_id` in the current round of the pool of `token_id`
+    fn flash_loan_shares_of(&self, account_id: &AccountId, token_id: &AccountId) -> Balance {
+        let round = self.flash_loan_pools.get(token_id).map(|p| p.round).unwrap_or(0);
+        match self.flash_loan_shares.get(&(account_id.clone(), token_id.clone())) {
+            Some((share_round, shares)) if share_round == round => shares,
+            _ => 0,
+        }
+    }
+
+    /// The amount of the token in the flash loan pool of `token_id`
+    pub(crate) fn flash_loan_pool_amount(&self, token_id: &AccountId) -> Balance {
+        self.flash_loan_pools.get(token_id).map(|p| p.amount.0).unwrap_or(0)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Enable flash loans of the underlying tokens with a fee of `fee / 10^15` of the lent
+    /// amount, or disable them with `None`
+    #[payable]
+    pub fn set_flash_loan_fee(&mut self, fee: Option<U128>) {
+        assert_one_yocto();
+        self.assert_owner();
+        let fee = fee.map(|f| f.0);
+        if let Some(fee) = fee {
+            if fee > FEE_DENOMINATOR {
+                panic!("Expected the flash loan fee to be at most {}", FEE_DENOMINATOR);
+            }
+        }
+        self.set_info.flash_loan_fee = fee;
+    }
+
+    pub fn get_flash_loan_fee(&self) -> Option<U128> {
+        self.set_info.flash_loan_fee.map(|f| f.into())
+    }
+
+    /// Allow `account_id` to take flash loans
+    #[payable]
+    pub fn add_flash_loan_borrower(&mut self, account_id: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        let account_id: AccountId = account_id.into();
+        if !self.set_info.flash_loan_borrowers.contains(&account_id) {
+            self.set_info.flash_loan_borrowers.push(account_id);
+        }
+    }
+
+    #[payable]
+    pub fn remove_flash_loan_borrower(&mut self, account_id: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.set_info.flash_loan_borrowers.retain(|a| a != account_id.as_ref());
+    }
+
+    pub fn get_flash_loan_borrowers(&self) -> Vec<AccountId> {
+        self.set_info.flash_loan_borrowers.clone()
+    }
+
+    pub fn get_flash_loan(&self) -> Option<FlashLoan> {
+        self.set_info.flash_loan.clone()
+    }
+
+    /// Opt `amount` of `token_id` from the caller's internal balance into flash loans. The
+    /// caller gets shares of the pool of the token, which earn nothing but bear the loss of a
+    /// loan which is not repaid. The attached deposit pays for the storage of the shares and
+    /// the rest is refunded
+    ///
+    /// return the shares received
+    #[payable]
+    pub fn provide_flash_liquidity(&mut self, token_id: ValidAccountId, amount: U128) -> U128 {
+        self.set_info.assert_no_flash_loan();
+        let account_id = env::predecessor_account_id();
+        let token_id: AccountId = token_id.into();
+        if is_native_near(&token_id) {
+            panic!("Native NEAR can not be flash loaned");
+        }
+        let amount: Balance = amount.into();
+        if amount == 0 {
+            panic!("Expected the amount to be greater than 0");
+        }
+        let initial_storage_usage = env::storage_usage();
+
+        let mut pool = self.set_info.flash_loan_pools.get(&token_id).unwrap_or_default();
+        let shares = if pool.shares.0 == 0 {
+            amount
+        } else {
+            mul_div(amount, pool.shares.0, pool.amount.0, false).unwrap()
+        };
+        if shares == 0 {
+            panic!("The amount is too small for a share of the pool");
+        }
+        let account_shares = self.set_info.flash_loan_shares_of(&account_id, &token_id);
+        self.subtract_balance(&account_id, &token_id, amount);
+        pool.amount = (pool.amount.0 + amount).into();
+        pool.shares = (pool.shares.0 + shares).into();
+        self.set_info.flash_loan_shares.insert(
+            &(account_id.clone(), token_id.clone()),
+            &(pool.round, account_shares + shares),
+        );
+        self.set_info.flash_loan_pools.insert(&token_id, &pool);
+        log!("@{} provided {} of {} for flash loans", account_id, amount, token_id);
+
+        let storage_cost =
+            Balance::from(env::storage_usage().saturating_sub(initial_storage_usage))
+                * env::storage_byte_cost();
+        let required = std::cmp::max(storage_cost, 1);
+        let deposit = env::attached_deposit();
+        if deposit < required {
+            panic!("Expected at least {} yoctoNEAR attached for the storage", required);
+        }
+        if deposit > storage_cost {
+            Promise::new(account_id).transfer(deposit - storage_cost);
+        }
+        shares.into()
+    }
+
+    /// Take `shares` of the caller, or all of them, out of the flash loan pool of `token_id`
+    /// into its internal balance. Blocked while a loan runs, so that the lent tokens cannot be
+    /// withdrawn before they come back. The storage freed by the last shares is refunded
+    ///
+    /// return the amount of the token withdrawn
+    #[payable]
+    pub fn withdraw_flash_liquidity(
+        &mut self,
+        token_id: ValidAccountId,
+        shares: Option<U128>,
+    ) -> U128 {
+        assert_one_yocto();
+        self.set_info.assert_no_flash_loan();
+        let account_id = env::predecessor_account_id();
+        let token_id: AccountId = token_id.into();
+        let account_shares = self.set_info.flash_loan_shares_of(&account_id, &token_id);
+        let shares = shares.map(|s| s.0).unwrap_or(account_shares);
+        if shares == 0 || shares > account_shares {
+            panic!("Expected the shares to be between 1 and the {} of the caller", account_shares);
+        }
+        let initial_storage_usage = env::storage_usage();
+
+        let mut pool = self.set_info.flash_loan_pools.get(&token_id).unwrap();
+        let amount = mul_div(shares, pool.amount.0, pool.shares.0, false).unwrap();
+        pool.amount = (pool.amount.0 - amount).into();
+        pool.shares = (pool.shares.0 - shares).into();
+        self.set_info.flash_loan_pools.insert(&token_id, &pool);
+        let key = (account_id.clone(), token_id.clone());
+        if shares == account_shares {
+            self.set_info.flash_loan_shares.remove(&key);
+        } else {
+            self.set_info.flash_loan_shares.insert(&key, &(pool.round, account_shares - shares));
+        }
+        self.increase_balance(&account_id, &token_id, amount);
+        log!("@{} withdrew {} of {} from flash loans", account_id, amount, token_id);
+
+        let storage_refund =
+            Balance::from(initial_storage_usage.saturating_sub(env::storage_usage()))
+                * env::storage_byte_cost();
+        if storage_refund > 0 {
+            Promise::new(account_id).transfer(storage_refund);
+        }
+        amount.into()
+    }
+
+    pub fn get_flash_loan_pool(&self, token_id: ValidAccountId) -> Option<FlashLoanPool> {
+        self.set_info.flash_loan_pools.get(token_id.as_ref())
+    }
+
+    /// The amount of `token_id` the shares of `account_id` in the flash loan pool are worth
+    pub fn get_flash_liquidity(
+        &self,
+        account_id: ValidAccountId,
+        token_id: ValidAccountId,
+    ) -> U128 {
+        let shares = self.set_info.flash_loan_shares_of(account_id.as_ref(), token_id.as_ref());
+        match self.set_info.flash_loan_pools.get(token_id.as_ref()) {
+            Some(pool) if shares > 0 => {
+                mul_div(shares, pool.amount.0, pool.shares.0, false).unwrap().into()
+            }
+            _ => 0.into(),
+        }
+    }
+
+    /// Lend `amount` of `token_id` held by the set to `receiver_id` with `ft_transfer_call`.
+    /// Only the borrowers allowed by the owner can call this, and only the liquidity of the
+    /// flash loan pool of the token can be lent out, never the internal balances or the locked
+    /// backing of the supply.
+    ///
+    /// The receiver repays by returning the tokens as unused from `ft_on_transfer`, or by
+    /// depositing them into its internal balance and calling `repay_flash_loan`, in both cases
+    /// before its `ft_on_transfer` resolves. The repayment has to include the fee, which
+    /// is split between the owner and the platform like the wrapping fee.
+    ///
+    /// Wrapping, unwrapping and the liquidity of the pool are blocked until the loan resolves
+    #[payable]
+    pub fn flash_loan(
+        &mut self,
+        token_id: ValidAccountId,
+        amount: U128,
+        receiver_id: ValidAccountId,
+        msg: String,
+    ) -> Promise {
+        assert_one_yocto();
+        let fee_rate =
+            self.set_info.flash_loan_fee.unwrap_or_else(|| panic!("Flash loans are disabled"));
+        self.set_info.assert_no_flash_loan();
+        let borrower_id = env::predecessor_account_id();
+        if !self.set_info.flash_loan_borrowers.contains(&borrower_id) {
+            panic!("Expected @{} to be an allowed flash loan borrower", borrower_id);
+        }
+        let token_id: AccountId = token_id.into();
+        if is_native_near(&token_id) {
+            panic!("Native NEAR can not be flash loaned");
+        }
+        let amount: Balance = amount.into();
+        if amount == 0 {
+            panic!("Expected the amount to be greater than 0");
+        }
+        let available = self.set_info.flash_loan_pool_amount(&token_id);
+        if amount > available {
+            panic!(
+                "Expected the amount to be at most the {} of {} in the flash loan pool",
+                available, token_id
+            );
+        }
+        let fee = mul_div(amount, fee_rate, FEE_DENOMINATOR, true).unwrap();
+        self.set_info.flash_loan =
+            Some(FlashLoan { token_id: token_id.clone(), amount: amount.into(), repaid: 0.into() });
+        log!("Lending {} of {} to @{} for a fee of {}", amount, token_id, receiver_id, fee);
+
+        ext_ft::ft_transfer_call(
+            receiver_id.into(),
+            amount.into(),
+            Some("flash loan".to_string()),
+            msg,
+            &token_id,
+            1,
+            env::prepaid_gas() - GAS_FOR_FLASH_LOAN,
+        )
+        .then(ext_self::resolve_flash_loan(
+            fee.into(),
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_FLASH_LOAN,
+        ))
+    }
+
+    /// Repay `amount` of the running flash loan from the caller's internal balance
+    #[payable]
+    pub fn repay_flash_loan(&mut self, amount: U128) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let loan = self
+            .set_info
+            .flash_loan
+            .clone()
+            .unwrap_or_else(|| panic!("There is no flash loan to repay"));
+        self.subtract_balance(&account_id, &loan.token_id, amount.0);
+        self.set_info.flash_loan =
+            Some(FlashLoan { repaid: (loan.repaid.0 + amount.0).into(), ..loan });
+        log!("@{} repaid {} of the flash loan", account_id, amount.0);
+    }
+
+    /// Check that the loan was repaid with the fee and credit the fee to the owner and the
+    /// platform. If less than the lent amount and the fee came back, the loan failed, and what
+    /// is missing of the lent amount is taken out of the pool, so that only its providers bear
+    /// the loss. Whatever came back above the lent amount is still credited as the fee.
+    /// The share of an owner or platform not registered for internal balances is locked as
+    /// backing, as nobody could claim it
+    ///
+    /// return whether the loan was repaid in full with the fee
+    #[private]
+    pub fn resolve_flash_loan(&mut self, fee: U128) -> bool {
+        let loan = self.set_info.flash_loan.take().unwrap();
+        let amount: Balance = loan.amount.into();
+        let returned = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            // `ft_transfer_call` returns the amount used by the receiver, the rest was refunded
+            PromiseResult::Successful(value) => {
+                let used = serde_json::from_slice::<U128>(&value).map(|u| u.0).unwrap_or(amount);
+                amount - std::cmp::min(used, amount)
+            }
+            PromiseResult::Failed => amount,
+        };
+        let repaid = returned + loan.repaid.0;
+
+        let fee_paid = repaid.saturating_sub(amount);
+        if fee_paid > 0 {
+            let (owner_fee, platform_fee) = self.flash_loan_fee_split(fee_paid);
+            let owner_id = self.owner_id.clone();
+            let platform_id = self.set_info.fee.platform_id.clone();
+            let owner_credited = self.credit_flash_loan_fee(&owner_id, &loan.token_id, owner_fee);
+            let platform_credited =
+                self.credit_flash_loan_fee(&platform_id, &loan.token_id, platform_fee);
+            emit_fee_charged(
+                FeeKind::FlashLoan,
+                &owner_id,
+                0,
+                owner_credited,
+                &platform_id,
+                0,
+                platform_credited,
+            );
+        }
+
+        if repaid < amount {
+            let mut pool = self.set_info.flash_loan_pools.get(&loan.token_id).unwrap();
+            pool.amount = (pool.amount.0 - (amount - repaid)).into();
+            // Nothing is left for the shares, so start a new round for the next providers
+            if pool.amount.0 == 0 {
+                pool.shares = 0.into();
+                pool.round += 1;
+            }
+            self.set_info.flash_loan_pools.insert(&loan.token_id, &pool);
+        }
+        if repaid < amount + fee.0 {
+            log!(
+                "The flash loan of {} of {} with a fee of {} was repaid with {}",
+                amount,
+                loan.token_id,
+                fee.0,
+                repaid
+            );
+            return false;
+        }
+        true
+    }
+}
+
+impl Contract {
+    /// Credit a share of the flash loan fee to the internal balance of `account_id`. If it is
+    /// not registered for internal balances, nobody could claim the share, so it backs the set
+    /// instead
+    ///
+    /// return the amounts credited
+    fn credit_flash_loan_fee(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        fee: Balance,
+    ) -> Vec<(AccountId, Balance)> {
+        let fee = vec![(token_id.clone(), fee)];
+        if self.credit_fee_underlying(account_id, fee.clone()) {
+            return fee;
+        }
+        log!("@{} is not registered for internal balances, locking its fee", account_id);
+        self.lock_backing(token_id, fee[0].1);
+        vec![]
+    }
+
+    /// Split the flash loan fee between the owner and the platform in proportion to their
+    /// wrapping fees, giving all of it to the owner if neither takes a wrapping fee
+    fn flash_loan_fee_split(&self, fee: Balance) -> (Balance, Balance) {
+        let set_fee = &self.set_info.fee;
+        let total = set_fee.owner_fee + set_fee.platform_fee;
+        if total == 0 {
+            return (fee, 0);
+        }
+        let platform_fee = mul_div(fee, set_fee.platform_fee, total, false).unwrap();
+        (fee - platform_fee, platform_fee)
+    }
+}
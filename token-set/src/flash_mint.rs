@@ -0,0 +1,191 @@
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId, Balance, Promise};
+
+use crate::events::{emit_fee_minted, emit_ft_burn, emit_ft_mint, emit_set_wrap, FeeKind};
+use crate::external::{
+    ext_flash_mint_receiver, ext_self, GAS_FOR_FLASH_MINT, GAS_FOR_RESOLVE_FLASH_MINT,
+};
+use crate::preview::to_token_amounts;
+use crate::token_set_info::FEE_DENOMINATOR;
+use crate::utils::mul_div;
+use crate::{Contract, ContractContract, SetInfo, TokenAmount};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+/// Set tokens flash minted to a receiver against the collateral of its internal balance
+pub struct FlashMint {
+    pub receiver_id: AccountId,
+    pub amount: U128,
+    /// The flash mint fee, burned with the repayment
+    pub fee: U128,
+    /// The wrapping fees of the owner and the platform, minted if the amount is not repaid
+    pub owner_fee: U128,
+    pub platform_fee: U128,
+    /// The number of units of the set the collateral was locked for
+    pub units: U128,
+    /// The underlying tokens moved from the internal balance of the receiver into the locked
+    /// backing
+    pub collateral: Vec<TokenAmount>,
+}
+
+impl SetInfo {
+    pub(crate) fn assert_no_flash_mint(&self) {
+        if self.flash_mint.is_some() {
+            panic!("The set has flash minted tokens outstanding");
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Enable flash minting with a fee of `fee / 10^15` of the minted amount, or disable it
+    /// with `None`
+    #[payable]
+    pub fn set_flash_mint_fee(&mut self, fee: Option<U128>) {
+        assert_one_yocto();
+        self.assert_owner();
+        let fee = fee.map(|f| f.0);
+        if let Some(fee) = fee {
+            if fee > FEE_DENOMINATOR {
+                panic!("Expected the flash mint fee to be at most {}", FEE_DENOMINATOR);
+            }
+        }
+        self.set_info.flash_mint_fee = fee;
+    }
+
+    pub fn get_flash_mint_fee(&self) -> Option<U128> {
+        self.set_info.flash_mint_fee.map(|f| f.into())
+    }
+
+    pub fn get_flash_mint(&self) -> Option<FlashMint> {
+        self.set_info.flash_mint.clone()
+    }
+
+    /// Mint `amount` set tokens to `receiver_id` and call `on_flash_mint` on it. The receiver
+    /// has to be the caller, and the underlying tokens for the amount and for the wrapping
+    /// fees on it are locked from its internal balance as collateral, so the minted tokens
+    /// are backed like wrapped ones.
+    ///
+    /// Once `on_flash_mint` returns, the receiver has to hold the amount plus the flash mint
+    /// fee. Both are burned and the collateral for the amount and the wrapping fees goes back
+    /// to its internal balance, while the backing of the burned fee stays with the set.
+    /// Otherwise the flash mint stands as a wrap of the collateral and the wrapping fees are
+    /// minted to the owner and the platform.
+    ///
+    /// Auctions and recompositions are blocked until the flash mint resolves
+    #[payable]
+    pub fn flash_mint(
+        &mut self,
+        amount: U128,
+        receiver_id: ValidAccountId,
+        msg: String,
+    ) -> Promise {
+        assert_one_yocto();
+        let fee_rate =
+            self.set_info.flash_mint_fee.unwrap_or_else(|| panic!("Flash minting is disabled"));
+        let receiver_id: AccountId = receiver_id.into();
+        let caller = env::predecessor_account_id();
+        if receiver_id != caller {
+            panic!("Expected the receiver of the flash mint to be the caller @{}", caller);
+        }
+        self.set_info.assert_ready();
+        self.set_info.assert_no_auction();
+        self.set_info.assert_no_flash_mint();
+        self.paused.assert_wrap_active();
+        self.assert_token_account_registered(&receiver_id);
+        let amount: Balance = amount.into();
+        let wrap_to_underlying_ratio = self.set_info.wrap_to_underlying_ratio;
+        if amount == 0 || amount % wrap_to_underlying_ratio != 0 {
+            panic!(
+                "Expected {} to be a non-zero multiple of the wrap to underlying ratio of {}",
+                amount, wrap_to_underlying_ratio
+            );
+        }
+        self.accrue_streaming_fees();
+
+        let fee = mul_div(amount, fee_rate, FEE_DENOMINATOR, true).unwrap();
+        let (_, owner_fee, platform_fee) = self.set_info.fee.mint_fee_split(amount);
+        let fee_units =
+            (owner_fee + platform_fee + wrap_to_underlying_ratio - 1) / wrap_to_underlying_ratio;
+        let units = amount / wrap_to_underlying_ratio + fee_units;
+        let collateral = self.decrease_potentials(units, &receiver_id);
+
+        self.token.internal_deposit(&receiver_id, amount);
+        emit_ft_mint(&receiver_id, amount, Some("flash mint"));
+        self.set_info.flash_mint = Some(FlashMint {
+            receiver_id: receiver_id.clone(),
+            amount: amount.into(),
+            fee: fee.into(),
+            owner_fee: owner_fee.into(),
+            platform_fee: platform_fee.into(),
+            units: units.into(),
+            collateral: to_token_amounts(collateral),
+        });
+        log!("Flash minting {} to @{} for a fee of {}", amount, receiver_id, fee);
+
+        ext_flash_mint_receiver::on_flash_mint(
+            caller,
+            amount.into(),
+            fee.into(),
+            msg,
+            &receiver_id,
+            0,
+            env::prepaid_gas() - GAS_FOR_FLASH_MINT,
+        )
+        .then(ext_self::resolve_flash_mint(
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_FLASH_MINT,
+        ))
+    }
+
+    /// Burn the flash minted amount and the fee from the receiver and release its collateral,
+    /// whatever the outcome of `on_flash_mint`. If the receiver does not hold both, or can no
+    /// longer take the collateral back into its internal balance, the flash mint stands as a
+    /// wrap
+    ///
+    /// return whether the flash mint was repaid with the fee
+    #[private]
+    pub fn resolve_flash_mint(&mut self) -> bool {
+        let flash_mint = self.set_info.flash_mint.take().unwrap();
+        let receiver_id = flash_mint.receiver_id;
+        let (amount, fee) = (flash_mint.amount.0, flash_mint.fee.0);
+        let balance = self.token.accounts.get(&receiver_id).unwrap_or(0);
+        let registered = self.accounts.get_account(&receiver_id).is_some();
+
+        if balance < amount + fee || !registered {
+            let owner_id = self.owner_id.clone();
+            let platform_id = self.set_info.fee.platform_id.clone();
+            let (owner_fee, platform_fee) = (flash_mint.owner_fee.0, flash_mint.platform_fee.0);
+            self.token.internal_deposit(&owner_id, owner_fee);
+            self.token.internal_deposit(&platform_id, platform_fee);
+            emit_ft_mint(&owner_id, owner_fee, Some("mint fee"));
+            emit_ft_mint(&platform_id, platform_fee, Some("mint fee"));
+            emit_fee_minted(FeeKind::Mint, &owner_id, owner_fee, &platform_id, platform_fee);
+            emit_set_wrap(&receiver_id, &receiver_id, amount, flash_mint.collateral);
+            log!(
+                "@{} held {} of the {} flash minted with a fee of {}, keeping them as wrapped",
+                receiver_id,
+                balance,
+                amount,
+                fee
+            );
+            return false;
+        }
+
+        self.token.internal_withdraw(&receiver_id, amount + fee);
+        emit_ft_burn(&receiver_id, amount + fee, Some("flash mint"));
+        // Ratios scaled down by the streaming fees meanwhile release less than was locked
+        let released = self.pricing().consumed_amounts(flash_mint.units.0);
+        for (collateral, (_, current)) in flash_mint.collateral.iter().zip(released) {
+            let amount = std::cmp::min(collateral.amount.0, current)
+                .min(self.locked_backing_of(&collateral.token_id));
+            self.release_backing(&collateral.token_id, amount);
+            self.increase_balance(&receiver_id, &collateral.token_id, amount);
+        }
+        true
+    }
+}
@@ -15,48 +15,153 @@ NOTES:
   - To prevent the deployed contract from being modified or deleted, it should not have any access
     keys on its account.
 */
-use account_info::AccountInfo;
+use account_info::{AccountInfo, INTERNAL_TOTALS_PREFIX, INTERNAL_TOTALS_SEEDING_KEY};
 use near_account::{AccountInfoTrait, Accounts, NearAccounts};
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
 use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
 };
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_contract_standards::fungible_token::FungibleToken;
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
 use near_internal_balances_plugin::impl_near_balance_plugin;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LazyOption, Vector};
+use near_sdk::collections::{LazyOption, LookupMap, UnorderedMap, Vector};
 use near_sdk::json_types::{Base64VecU8, ValidAccountId, U128};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, PanicOnDefault, PromiseOrValue,
+    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, PanicOnDefault, Promise,
+    PromiseOrValue,
 };
-use shared::{MetadataReference, TokenWithRatio, TokenWithRatioValid};
+use shared::{MetadataReference, SetConfig, TokenWithRatio, TokenWithRatioValid};
+use utils::assert_valid_delay;
 
+use events::{emit_ft_burn, emit_ft_transfer, emit_metadata_updated};
 
 mod account_info;
+mod auction;
+mod convert;
+mod decimals;
+mod deposit;
+mod escrow;
+mod events;
+mod external;
+mod fees;
+mod flash_loan;
+mod flash_mint;
+mod migrate;
+mod multi_set;
+mod native;
+mod ownership;
+mod pause;
+mod platform;
+mod preview;
+mod recompose;
+mod reconcile;
 mod token_set_info;
 mod utils;
+mod withdraw;
 
-pub use token_set_info::WRAP_TO_UNDERLYING_RATIO;
+pub use auction::{Auction, AuctionStatus, MAX_AUCTION_DISCOUNT, MAX_AUCTION_DURATION};
+pub use deposit::{DepositAction, DepositMsg};
+pub use escrow::{AccountEscrow, Escrow};
+pub use fees::{AccruedFees, PendingFeeChange};
+pub use flash_loan::{FlashLoan, FlashLoanPool};
+pub use flash_mint::FlashMint;
+pub use multi_set::{HostedSet, HostedSetView, MultiToken, MAX_SET_ID_LEN};
+pub use native::NATIVE_NEAR_ID;
+pub use ownership::PendingOwner;
+pub use pause::PauseStatus;
+pub use platform::PendingPlatformFeeChange;
+pub use preview::{UnwrapPreview, WrapPreview};
+pub use recompose::PendingRecomposition;
+pub use reconcile::TokenReconciliation;
+pub use token_set_info::{
+    DEFAULT_DECIMALS, DEFAULT_ESCROW_WINDOW, DEFAULT_FEE_CHANGE_DELAY, DEFAULT_MAX_FEE_INCREASE,
+    DEFAULT_RECOMPOSITION_NOTICE, MAX_DELAY, MIN_DELAY, WRAP_TO_UNDERLYING_RATIO,
+};
 
 near_sdk::setup_alloc!();
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
-/// Contains the fees for minting tokens.
+/// Contains the fees for minting and redeeming tokens.
 pub struct FeeReceiver {
     /// The fee for the owner of the token set
     owner_fee: u128,
     /// The fee for the "platform" which created the token set contract
     platform_fee: u128,
+    /// The maximum fee the platform can raise its fee to
+    max_platform_fee: u128,
+    /// The fee for the owner of the token set when redeeming tokens
+    owner_redeem_fee: u128,
+    /// The fee for the "platform" when redeeming tokens
+    platform_redeem_fee: u128,
+    /// Whether the redemption fee is taken out of the underlying tokens instead of the set tokens
+    redeem_fee_in_components: bool,
+    /// The streaming fee for the owner, charged per second on the total supply
+    owner_streaming_fee: u128,
+    /// The streaming fee for the "platform", charged per second on the total supply
+    platform_streaming_fee: u128,
     /// The platform account to receive te token
     platform_id: AccountId,
     /// Whether the fee can be updated after instantiation
     updatable: bool,
 }
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SetStatus {
+    /// The set can be wrapped and unwrapped
+    Ready,
+    /// The set is waiting on the decimals of the underlying tokens to normalize its ratios
+    PendingMetadata,
+    /// The metadata of an underlying token could not be fetched, so the set cannot be used
+    Failed,
+}
+
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct SetInfo {
     ratios: Vector<TokenWithRatio>,
     fee: FeeReceiver,
+    /// The number of decimals of the set token
+    decimals: u8,
+    /// The amount of set tokens minted for one unit of the underlying ratios
+    wrap_to_underlying_ratio: u128,
+    status: SetStatus,
+    /// The block timestamp, in nanoseconds, up to which the streaming fees were minted
+    last_fee_accrual: u64,
+    /// The number of seconds between proposing a fee change and applying it
+    fee_change_delay: u64,
+    /// The maximum amount by which a fee can increase in a single change
+    max_fee_increase: u128,
+    pending_fee_change: Option<PendingFeeChange>,
+    pending_platform_fee_change: Option<PendingPlatformFeeChange>,
+    /// The platform account proposed by the current platform account, if any
+    pending_platform_id: Option<AccountId>,
+    /// The number of seconds between proposing a recomposition and executing it
+    recomposition_notice: u64,
+    pending_recomposition: Option<PendingRecomposition>,
+    /// The auction rebalancing the backing, if any
+    auction: Option<Auction>,
+    /// The amount of each token locked as backing of the supply, apart from the internal
+    /// balances
+    locked_backing: UnorderedMap<AccountId, Balance>,
+    /// The fee for flash loans of the underlying tokens, if enabled
+    flash_loan_fee: Option<u128>,
+    /// The accounts the owner allows to take flash loans
+    flash_loan_borrowers: Vec<AccountId>,
+    /// The underlying token lent out, if any
+    flash_loan: Option<FlashLoan>,
+    /// The liquidity opted into flash loans, by token
+    flash_loan_pools: UnorderedMap<AccountId, FlashLoanPool>,
+    /// The round and the shares of each provider in the flash loan pools, by provider and token
+    flash_loan_shares: LookupMap<(AccountId, AccountId), (u64, Balance)>,
+    /// The fee for flash minting set tokens, if enabled
+    flash_mint_fee: Option<u128>,
+    /// The set tokens flash minted and not resolved yet, if any
+    flash_mint: Option<FlashMint>,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, PanicOnDefault)]
@@ -65,6 +170,27 @@ pub struct SetMetadata {
     ft_metadata: FungibleTokenMetadata,
     set_fee: FeeReceiver,
     set_ratios: Vec<TokenWithRatio>,
+    wrap_to_underlying_ratio: U128,
+    set_status: SetStatus,
+    pending_fee_change: Option<PendingFeeChange>,
+    pending_platform_fee_change: Option<PendingPlatformFeeChange>,
+    pending_platform_id: Option<AccountId>,
+    pending_recomposition: Option<PendingRecomposition>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenAmount {
+    pub token_id: AccountId,
+    pub amount: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SetBacking {
+    pub total_supply: U128,
+    /// The amount of each token locked as backing of the total supply
+    pub locked_backing: Vec<TokenAmount>,
 }
 
 #[near_bindgen]
@@ -75,6 +201,27 @@ pub struct Contract {
     metadata: LazyOption<FungibleTokenMetadata>,
     accounts: Accounts<AccountInfo>,
     set_info: SetInfo,
+    /// The owner proposed by `propose_owner`, if any
+    pending_owner: Option<PendingOwner>,
+    /// The account which can pause the set alongside the owner
+    guardian_id: Option<AccountId>,
+    paused: PauseStatus,
+    /// The underlying tokens of force closed accounts which they can still claim
+    escrows: UnorderedMap<AccountId, Escrow>,
+    /// The total escrowed amount of each token over all closed accounts
+    escrow_totals: LookupMap<AccountId, Balance>,
+    /// The number of seconds a closed account can claim its escrow for
+    escrow_window: u64,
+    /// The sets hosted by the contract as a NEP-245 multi-token, by set id
+    hosted_sets: UnorderedMap<String, HostedSet>,
+    /// The amount of each token locked as backing of all the hosted sets
+    hosted_backing: LookupMap<AccountId, Balance>,
+    /// The total internal balance of each token over all accounts, kept up to date by
+    /// `InternalBalances`
+    internal_totals: LookupMap<AccountId, Balance>,
+    /// The index of the next account whose internal balances `seed_internal_totals` adds to
+    /// the running totals, while a migrated set seeds them
+    internal_totals_seeding: LazyOption<u64>,
 }
 
 // Implement the internal balance traits
@@ -96,7 +243,9 @@ impl Contract {
         owner_fee: U128,
         updatable_fee: Option<bool>,
         metadata_reference: Option<MetadataReference>,
+        config: Option<SetConfig>,
     ) -> Self {
+        let config = config.unwrap_or_default();
         Self::new(
             owner_id,
             FungibleTokenMetadata {
@@ -108,54 +257,115 @@ impl Contract {
                 reference_hash: metadata_reference
                     .as_ref()
                     .map(|r| Base64VecU8::from(r.reference_hash.clone())),
-                decimals: 24,
+                decimals: config.decimals.unwrap_or(DEFAULT_DECIMALS),
             },
             set_ratios,
             FeeReceiver {
                 platform_fee: platform_fee.0,
+                max_platform_fee: config.max_platform_fee.map(|f| f.0).unwrap_or(platform_fee.0),
                 owner_fee: owner_fee.0,
+                owner_redeem_fee: config.owner_redeem_fee.map(|f| f.0).unwrap_or(0),
+                platform_redeem_fee: config.platform_redeem_fee.map(|f| f.0).unwrap_or(0),
+                redeem_fee_in_components: config.redeem_fee_in_components.unwrap_or(false),
+                owner_streaming_fee: config.owner_streaming_fee.map(|f| f.0).unwrap_or(0),
+                platform_streaming_fee: config.platform_streaming_fee.map(|f| f.0).unwrap_or(0),
                 platform_id: platform_id.to_string(),
                 updatable: updatable_fee.unwrap_or(false),
             },
+            &config,
         )
     }
 
+    /// Wrap the caller's internal balances into `amount` set tokens (defaults to the maximum).
+    /// If `round_down` is set, the amount is rounded down to a multiple of the wrap to
+    /// underlying ratio instead of failing.
+    ///
+    /// return the amount of set tokens wrapped
     #[payable]
-    pub fn wrap(&mut self, amount: Option<U128>) {
+    pub fn wrap(&mut self, amount: Option<U128>, round_down: Option<bool>) -> U128 {
         assert_one_yocto();
-        self.wrap_internal(&self.owner_id.clone(), amount.map(|a| a.0));
+        let caller = env::predecessor_account_id();
+        self.wrap_internal(
+            &self.owner_id.clone(),
+            &caller,
+            &caller,
+            amount.map(|a| a.0),
+            round_down.unwrap_or(false),
+        )
+        .into()
     }
 
+    /// Wrap the caller's internal balances and mint the set tokens to `receiver_id`
     #[payable]
-    pub fn unwrap(&mut self, amount: U128) {
+    pub fn wrap_to(
+        &mut self,
+        receiver_id: ValidAccountId,
+        amount: Option<U128>,
+        round_down: Option<bool>,
+    ) -> U128 {
         assert_one_yocto();
-        self.unwrap_token(amount.into())
+        let caller = env::predecessor_account_id();
+        self.wrap_internal(
+            &self.owner_id.clone(),
+            &caller,
+            receiver_id.as_ref(),
+            amount.map(|a| a.0),
+            round_down.unwrap_or(false),
+        )
+        .into()
     }
 
+    /// Unwrap `amount` of the caller's set tokens into the internal balances.
+    /// If `round_down` is set, only the part of the amount divisible by the wrap to underlying
+    /// ratio is burned and the remainder stays in the caller's balance. Otherwise the amount net
+    /// of a redemption fee taken in set tokens has to be divisible by the ratio.
+    ///
+    /// return the amount of set tokens redeemed, including the redemption fee
     #[payable]
-    pub fn update_owner_fee(&mut self, new_fee: u128) {
+    pub fn unwrap(&mut self, amount: U128, round_down: Option<bool>) -> U128 {
         assert_one_yocto();
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.owner_id,
-            "Only the owner can update the fee"
-        );
+        let caller = env::predecessor_account_id();
+        self.unwrap_token(&caller, &caller, amount.into(), round_down.unwrap_or(false)).into()
+    }
 
-        self.change_owner_fee(new_fee);
+    /// Unwrap the caller's set tokens and credit the underlying tokens to the internal balance
+    /// of `receiver_id`
+    #[payable]
+    pub fn unwrap_to(
+        &mut self,
+        receiver_id: ValidAccountId,
+        amount: U128,
+        round_down: Option<bool>,
+    ) -> U128 {
+        assert_one_yocto();
+        let caller = env::predecessor_account_id();
+        self.unwrap_token(&caller, receiver_id.as_ref(), amount.into(), round_down.unwrap_or(false))
+            .into()
     }
 
-    // TODO: let's think about,
-    // if there account was deleted that means we have to do something with the balance
-    // maybe we j transfer to platform?
-    fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
-        log!("Closed @{} with {}", account_id, balance);
-        let platform_id = self.set_info.fee.platform_id.clone();
-        self.on_burn(platform_id, balance);
+    /// Unwrap `amount` set tokens and transfer the underlying tokens to `receiver_id`
+    /// (defaults to the caller) instead of crediting the internal balances.
+    /// If `msg` is given, `ft_transfer_call` is used for every transfer.
+    /// Failed or refunded transfers are put back into the internal balance of the caller.
+    /// Requires one yoctoNEAR attached for each underlying token transferred.
+    #[payable]
+    pub fn unwrap_and_withdraw(
+        &mut self,
+        amount: U128,
+        receiver_id: Option<ValidAccountId>,
+        msg: Option<String>,
+    ) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let receiver_id: AccountId =
+            receiver_id.map(|r| r.into()).unwrap_or_else(|| account_id.clone());
+        self.unwrap_and_withdraw_internal(&account_id, &receiver_id, amount.into(), msg)
     }
 
-    fn on_tokens_burned(&mut self, account_id: AccountId, amount: Balance) {
-        log!("Account @{} burned {}", account_id, amount);
-        self.on_burn(account_id, amount);
+    /// The underlying tokens of a force closed account are escrowed, so that the account can
+    /// claim them after registering again. Once the escrow window passes they go to the platform
+    fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
+        emit_ft_burn(&account_id, balance, Some("account closed"));
+        self.escrow_closed_account(&account_id, balance);
     }
 }
 
@@ -167,6 +377,7 @@ impl Contract {
         metadata: FungibleTokenMetadata,
         set_ratios: Vec<TokenWithRatioValid>,
         set_initial_fee: FeeReceiver,
+        config: &SetConfig,
     ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
 
@@ -176,12 +387,25 @@ impl Contract {
         metadata.assert_valid();
         let numb_tokens = set_ratios.len();
 
+        let escrow_window = config.escrow_window.unwrap_or(DEFAULT_ESCROW_WINDOW);
+        assert_valid_delay("escrow window", escrow_window);
+
         let mut this = Self {
             owner_id: owner_id.to_string(),
             token: FungibleToken::new(b"a".to_vec()),
             metadata: LazyOption::new(b"m".to_vec(), Some(&metadata)),
             accounts: Accounts::new(),
-            set_info: SetInfo::new(set_ratios, set_initial_fee),
+            set_info: SetInfo::new(b"", set_ratios, set_initial_fee, metadata.decimals, config),
+            pending_owner: None,
+            guardian_id: None,
+            paused: PauseStatus::default(),
+            escrows: UnorderedMap::new(b"escrow".to_vec()),
+            escrow_totals: LookupMap::new(b"escrow-totals".to_vec()),
+            escrow_window,
+            hosted_sets: UnorderedMap::new(b"hosted-sets".to_vec()),
+            hosted_backing: LookupMap::new(b"hosted-backing".to_vec()),
+            internal_totals: LookupMap::new(INTERNAL_TOTALS_PREFIX),
+            internal_totals_seeding: LazyOption::new(INTERNAL_TOTALS_SEEDING_KEY, None),
         };
 
         // Register the platform and owner with the token
@@ -196,12 +420,143 @@ impl Contract {
 
         this.accounts.default_min_storage_bal = account_storage_min;
 
+        if config.ratios_in_whole_units.unwrap_or(false) {
+            this.fetch_ratio_decimals();
+        }
+
         this
     }
 }
 
-near_contract_standards::impl_fungible_token_core!(Contract, token, on_tokens_burned);
-near_contract_standards::impl_fungible_token_storage!(Contract, token, on_account_closed);
+// Implemented by hand instead of with `impl_fungible_token_core!` so that transfers can be paused
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>) {
+        self.paused.assert_transfer_active();
+        emit_ft_transfer(
+            &env::predecessor_account_id(),
+            receiver_id.as_ref(),
+            amount.0,
+            memo.as_deref(),
+        );
+        self.token.ft_transfer(receiver_id, amount, memo)
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.paused.assert_transfer_active();
+        emit_ft_transfer(
+            &env::predecessor_account_id(),
+            receiver_id.as_ref(),
+            amount.0,
+            memo.as_deref(),
+        );
+        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: ValidAccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        receiver_id: ValidAccountId,
+        amount: U128,
+    ) -> U128 {
+        let sender_id: AccountId = sender_id.into();
+        let receiver: AccountId = receiver_id.clone().into();
+        let (used_amount, burned_amount) =
+            self.token.internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
+        // Reverse the refunded part of the `ft_transfer` emitted by `ft_transfer_call`. If the
+        // sender closed its account, the refund is burned from the receiver instead and
+        // `used_amount` is the whole amount
+        emit_ft_transfer(&receiver, &sender_id, amount.0 - used_amount, Some("refund"));
+        if burned_amount > 0 {
+            emit_ft_burn(&receiver, burned_amount, Some("refund to a closed account"));
+            self.on_burn(sender_id, burned_amount);
+        }
+        used_amount.into()
+    }
+}
+
+// Implemented by hand instead of with `impl_fungible_token_storage!` so that the storage of the
+// escrow of a force closed account is paid from its storage refund
+#[near_bindgen]
+impl StorageManagement for Contract {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<ValidAccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        self.token.storage_deposit(account_id, registration_only)
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        self.token.storage_withdraw(amount)
+    }
+
+    /// Unregister the caller. With `force` the set tokens of the caller are burned and the
+    /// underlying tokens are escrowed for it, whose storage is taken out of the refund as far as
+    /// the refund goes
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = match self.token.accounts.get(&account_id) {
+            Some(balance) => balance,
+            None => {
+                log!("The account {} is not registered", &account_id);
+                return false;
+            }
+        };
+        if balance > 0 && !force.unwrap_or(false) {
+            env::panic(b"Can't unregister the account with the positive balance without force");
+        }
+        self.token.accounts.remove(&account_id);
+        self.token.total_supply -= balance;
+
+        let initial_storage_usage = env::storage_usage();
+        self.on_account_closed(account_id.clone(), balance);
+        let escrow_storage_cost =
+            Balance::from(env::storage_usage().saturating_sub(initial_storage_usage))
+                * env::storage_byte_cost();
+        let refund = self.storage_balance_bounds().min.0 + 1;
+        let charged = std::cmp::min(escrow_storage_cost, refund);
+        if charged > 0 {
+            log!("Charged @{} {} for the storage of its escrow", account_id, charged);
+        }
+        if refund > charged {
+            Promise::new(account_id).transfer(refund - charged);
+        }
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.token.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
+        self.token.storage_balance_of(account_id)
+    }
+}
 
 #[near_bindgen]
 impl FungibleTokenMetadataProvider for Contract {
@@ -211,7 +566,7 @@ impl FungibleTokenMetadataProvider for Contract {
 }
 
 impl Contract {
-    fn assert_owner(&self) {
+    pub(crate) fn assert_owner(&self) {
         assert_eq!(
             self.owner_id,
             env::predecessor_account_id(),
@@ -228,9 +583,32 @@ impl Contract {
             ft_metadata: self.metadata.get().unwrap(),
             set_fee: self.set_info.fee.clone(),
             set_ratios: self.set_info.ratios.to_vec(),
+            wrap_to_underlying_ratio: self.set_info.wrap_to_underlying_ratio.into(),
+            set_status: self.set_info.status.clone(),
+            pending_fee_change: self.set_info.pending_fee_change.clone(),
+            pending_platform_fee_change: self.set_info.pending_platform_fee_change.clone(),
+            pending_platform_id: self.set_info.pending_platform_id.clone(),
+            pending_recomposition: self.set_info.pending_recomposition.clone(),
+        }
+    }
+
+    pub fn set_backing(&self) -> SetBacking {
+        SetBacking {
+            total_supply: self.token.total_supply.into(),
+            locked_backing: self
+                .set_info
+                .locked_backing
+                .iter()
+                .map(|(token_id, amount)| TokenAmount { token_id, amount: amount.into() })
+                .collect(),
         }
     }
 
+    /// The total internal balance of `token_id` over all accounts
+    pub fn internal_balance_total(&self, token_id: ValidAccountId) -> U128 {
+        self.internal_totals.get(token_id.as_ref()).unwrap_or(0).into()
+    }
+
     pub fn update_metadata_reference(&mut self, new_reference: Option<MetadataReference>) {
         self.assert_owner();
         let mut metadata = self.metadata.get().unwrap();
@@ -244,6 +622,7 @@ impl Contract {
             metadata.reference_hash = None;
         }
         self.metadata.set(&metadata);
+        emit_metadata_updated(&metadata.reference, &metadata.reference_hash);
     }
 }
 
@@ -251,11 +630,16 @@ impl Contract {
 mod tests {
     use std::convert::TryFrom;
 
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
     use near_sdk::{testing_env, Balance};
-    use near_sdk::{MockedBlockchain, VMConfig};
+    use near_sdk::{MockedBlockchain, PromiseResult, RuntimeFeesConfig, VMConfig};
+    use std::collections::HashMap;
 
     use super::*;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::serde_json::json;
+
+    use crate::migrate::{LegacyContract, LegacyFeeReceiver, LegacySetInfo, LegacyTokenWithRatio};
 
     fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
         let mut builder = VMContextBuilder::new();
@@ -284,6 +668,105 @@ mod tests {
         context.attached_deposit(1);
     }
 
+    /// Credit `amount` of `token_id` to `account` and opt it into the flash loan pool, leaving
+    /// the context with one yoctoNEAR attached
+    fn provide_flash_liquidity(
+        contract: &mut Contract,
+        context: &mut VMContextBuilder,
+        account: ValidAccountId,
+        token_id: ValidAccountId,
+        amount: Balance,
+    ) {
+        contract.increase_balance(&account.to_string(), &token_id.to_string(), amount);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10u128.pow(24))
+            .predecessor_account_id(account)
+            .build());
+        contract.provide_flash_liquidity(token_id, amount.into());
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+    }
+
+    /// The NEP-297 events logged by the last call
+    fn get_events() -> Vec<near_sdk::serde_json::Value> {
+        get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| near_sdk::serde_json::from_str(event).unwrap())
+            .collect()
+    }
+
+    /// Register `account` with `amount` of `token_id` in its internal balance and make it the
+    /// caller, with one yoctoNEAR attached
+    fn fund_user(
+        contract: &mut Contract,
+        context: &mut VMContextBuilder,
+        account: ValidAccountId,
+        token_id: &ValidAccountId,
+        amount: Balance,
+    ) {
+        register_user(contract, context, account.clone());
+        contract.increase_balance(&account.to_string(), &token_id.to_string(), amount);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(account)
+            .build());
+    }
+
+    /// The ratios of a set of one `accounts(5)` per unit
+    fn single_token_ratios() -> Vec<TokenWithRatioValid> {
+        vec![TokenWithRatioValid { token_id: accounts(5), ratio: 1.into(), denominator: None }]
+    }
+
+    /// A set with a 1% platform fee and updatable fees, whose changes apply after an hour and
+    /// raise a fee by at most 2%. The owner is the caller at timestamp 0, with one yoctoNEAR
+    /// attached
+    fn new_set_with_fee_changes() -> (Contract, VMContextBuilder) {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let contract = new_set_with_fees(
+            single_token_ratios(),
+            10_000_000_000_000.into(),
+            0.into(),
+            Some(true),
+            SetConfig {
+                fee_change_delay: Some(MIN_DELAY),
+                max_fee_increase: Some(20_000_000_000_000.into()),
+                ..Default::default()
+            },
+        );
+        testing_env!(context.attached_deposit(1).build());
+        (contract, context)
+    }
+
+    /// A set owned by `accounts(2)` with `accounts(4)` as the platform and no mint fees
+    fn new_set(set_ratios: Vec<TokenWithRatioValid>, config: SetConfig) -> Contract {
+        new_set_with_fees(set_ratios, 0.into(), 0.into(), None, config)
+    }
+
+    fn new_set_with_fees(
+        set_ratios: Vec<TokenWithRatioValid>,
+        platform_fee: U128,
+        owner_fee: U128,
+        updatable_fee: Option<bool>,
+        config: SetConfig,
+    ) -> Contract {
+        Contract::new_default_meta(
+            accounts(2),
+            "YY".to_string(),
+            "YY".to_string(),
+            None,
+            set_ratios,
+            platform_fee,
+            accounts(4),
+            owner_fee,
+            updatable_fee,
+            None,
+            Some(config),
+        )
+    }
+
     #[test]
     fn test_new() {
         let mut context = get_context(accounts(1));
@@ -297,12 +780,13 @@ mod tests {
             "YOUR MOM".to_string(),
             "YOUR MOM".to_string(),
             None,
-            vec![TokenWithRatioValid { token_id, ratio: 1 }],
+            vec![TokenWithRatioValid { token_id, ratio: 1.into(), denominator: None }],
             0.into(),
             platform_id,
             0.into(),
             None,
             None,
+            None,
         );
         testing_env!(context.is_view(true).build());
         assert_eq!(contract.ft_total_supply().0, 0);
@@ -328,15 +812,180 @@ mod tests {
             "YOUR MOM".to_string(),
             None,
             vec![
-                TokenWithRatioValid { token_id: accounts(0), ratio: 1 },
-                TokenWithRatioValid { token_id: accounts(0), ratio: 1 },
+                TokenWithRatioValid { token_id: accounts(0), ratio: 1.into(), denominator: None },
+                TokenWithRatioValid { token_id: accounts(0), ratio: 1.into(), denominator: None },
             ],
             0.into(),
             accounts(1),
             0.into(),
             None,
             None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_custom_decimals_and_wrap_ratio() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new_default_meta(
+            accounts(1),
+            "YY".to_string(),
+            "YY".to_string(),
+            None,
+            single_token_ratios(),
+            0.into(),
+            accounts(4),
+            0.into(),
+            None,
+            None,
+            Some(SetConfig {
+                decimals: Some(6),
+                wrap_to_underlying_ratio: Some(1_000.into()),
+                ..Default::default()
+            }),
+        );
+        let meta = contract.set_metadata();
+        assert_eq!(meta.ft_metadata.decimals, 6);
+        assert_eq!(meta.wrap_to_underlying_ratio.0, 1_000);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Expected the wrap to underlying ratio to be at most one whole set token"
+    )]
+    fn test_wrap_ratio_above_one_token() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        Contract::new_default_meta(
+            accounts(1),
+            "YY".to_string(),
+            "YY".to_string(),
+            None,
+            single_token_ratios(),
+            0.into(),
+            accounts(4),
+            0.into(),
+            None,
+            None,
+            Some(SetConfig {
+                decimals: Some(2),
+                wrap_to_underlying_ratio: Some(1_000.into()),
+                ..Default::default()
+            }),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The set is waiting for the metadata of its underlying tokens")]
+    fn test_wrap_pending_metadata() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(
+            single_token_ratios(),
+            SetConfig { ratios_in_whole_units: Some(true), ..Default::default() },
+        );
+        assert_eq!(contract.set_metadata().set_status, SetStatus::PendingMetadata);
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 10);
+        contract.wrap(None, None);
+    }
+
+    #[test]
+    fn test_normalize_ratios() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = new_set(
+            vec![
+                TokenWithRatioValid { token_id: accounts(5), ratio: 1.into(), denominator: None },
+                TokenWithRatioValid { token_id: accounts(3), ratio: 2.into(), denominator: None },
+            ],
+            SetConfig::default(),
         );
+        contract.set_info.normalize_ratios(vec![6, 18]).unwrap();
+
+        // One whole set token is 10^24 / 10^6 units of the set
+        let ratios = contract.set_metadata().set_ratios;
+        assert_eq!((ratios[0].ratio.0, ratios[0].denominator.0), (1, 1_000_000_000_000));
+        assert_eq!(ratios[0].decimals, Some(6));
+        assert_eq!((ratios[1].ratio.0, ratios[1].denominator.0), (2, 1));
+        assert_eq!(ratios[1].decimals, Some(18));
+    }
+
+    #[test]
+    fn test_normalize_ratios_error_leaves_ratios() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = new_set(
+            vec![
+                TokenWithRatioValid { token_id: accounts(5), ratio: 1.into(), denominator: None },
+                TokenWithRatioValid { token_id: accounts(3), ratio: 2.into(), denominator: None },
+            ],
+            SetConfig::default(),
+        );
+        assert!(contract.set_info.normalize_ratios(vec![6, 39]).is_err());
+
+        // The first ratio is not normalized on its own
+        let ratios = contract.set_metadata().set_ratios;
+        assert_eq!((ratios[0].ratio.0, ratios[0].denominator.0), (1, 1));
+        assert_eq!(ratios[0].decimals, None);
+        assert_eq!((ratios[1].ratio.0, ratios[1].denominator.0), (2, 1));
+    }
+
+    #[test]
+    fn test_retry_ratio_decimals() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = new_set(
+            single_token_ratios(),
+            SetConfig { ratios_in_whole_units: Some(true), ..Default::default() },
+        );
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.resolve_ratio_decimals();
+        assert_eq!(contract.set_metadata().set_status, SetStatus::Failed);
+        assert_eq!(get_logs(), vec![format!("Failed to fetch the metadata of {}", accounts(5))]);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.retry_ratio_decimals();
+        assert_eq!(contract.set_metadata().set_status, SetStatus::PendingMetadata);
+
+        let metadata = json!({
+            "spec": "ft-1.0.0",
+            "name": "Token",
+            "symbol": "TKN",
+            "decimals": 18,
+        });
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).attached_deposit(0).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&metadata).unwrap())]
+        );
+        contract.resolve_ratio_decimals();
+        let metadata = contract.set_metadata();
+        assert_eq!(metadata.set_status, SetStatus::Ready);
+        assert_eq!(metadata.set_ratios[0].decimals, Some(18));
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the metadata fetch of the set to have failed")]
+    fn test_retry_ratio_decimals_not_failed() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = new_set(
+            single_token_ratios(),
+            SetConfig { ratios_in_whole_units: Some(true), ..Default::default() },
+        );
+        testing_env!(context.attached_deposit(1).build());
+        contract.retry_ratio_decimals();
     }
 
     #[test]
@@ -349,12 +998,13 @@ mod tests {
             "YY".to_string(),
             "YY".to_string(),
             None,
-            vec![TokenWithRatioValid { token_id, ratio: 1 }],
+            vec![TokenWithRatioValid { token_id, ratio: 1.into(), denominator: None }],
             0.into(),
             ValidAccountId::try_from(format!("platform{}", 1)).unwrap(),
             0.into(),
             None,
             None,
+            None,
         );
         let metadata_ref =
             MetadataReference { reference: "ref".to_string(), reference_hash: vec![] };
@@ -385,7 +1035,8 @@ mod tests {
             let token_ratios = (0..i)
                 .map(|x| TokenWithRatioValid {
                     token_id: ValidAccountId::try_from(format!("account{}", x)).unwrap(),
-                    ratio: 1,
+                    ratio: 1.into(),
+                    denominator: None,
                 })
                 .collect();
             println!("aa;{:?}", token_ratios);
@@ -400,6 +1051,7 @@ mod tests {
                 0.into(),
                 None,
                 None,
+                None,
             );
             let storage_min = contract.accounts_storage_balance_bounds().min.0;
 
@@ -413,76 +1065,2121 @@ mod tests {
     }
 
     #[test]
-    fn test_wrap_transfer() {
+    fn test_deposit_and_wrap() {
         let mut context = get_context(accounts(2));
         testing_env!(context.build());
-        let platform_id = accounts(4);
         let token_id = accounts(5);
-        let mut contract = Contract::new_default_meta(
-            accounts(2).into(),
-            "YOUR MOM".to_string(),
-            "YOUR MOM".to_string(),
-            None,
-            vec![TokenWithRatioValid { token_id: token_id.clone(), ratio: 1 }],
-            0.into(),
-            platform_id,
-            0.into(),
-            None,
-            None,
+        let mut contract = new_set(
+            vec![TokenWithRatioValid {
+                token_id: token_id.clone(),
+                ratio: 2.into(),
+                denominator: None,
+            }],
+            SetConfig::default(),
         );
-
-        // Paying for account registration, aka storage deposit
         register_user(&mut contract, &mut context, accounts(1));
+        register_user(&mut contract, &mut context, accounts(3));
 
-        let amount_transfer = 100;
-        contract.increase_balance(
-            &accounts(1).to_string(),
-            &token_id.clone().to_string(),
-            amount_transfer,
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(0)
+            .predecessor_account_id(token_id.clone())
+            .build());
+        let msg = near_sdk::serde_json::to_string(&DepositMsg {
+            action: Some(DepositAction::Wrap),
+            amount: Some((2 * WRAP_TO_UNDERLYING_RATIO).into()),
+            receiver_id: Some(accounts(3)),
+            ..Default::default()
+        })
+        .unwrap();
+        // The basket is not complete yet so the deposit stays in the internal balance
+        let unused = contract.ft_on_transfer(accounts(1), 3.into(), msg.clone());
+        assert!(matches!(unused, PromiseOrValue::Value(U128(0))));
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 0);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            3
         );
 
-        // Paying for account registration, aka storage deposit
-        register_user(&mut contract, &mut context, accounts(2));
-
-        // Paying for account registration, aka storage deposit
-        register_user(&mut contract, &mut context, accounts(4));
-
+        // Completing the basket wraps within the deposit
+        contract.ft_on_transfer(accounts(1), 2.into(), msg);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 2 * WRAP_TO_UNDERLYING_RATIO);
         assert_eq!(
-            contract
-                .get_ft_balance_internal(&accounts(1).to_string(), &token_id.clone().to_string()),
-            amount_transfer
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            1
         );
 
-        testing_env!(context
-            .storage_usage(env::storage_usage())
-            .attached_deposit(1)
-            .predecessor_account_id(accounts(1))
-            .build());
-        // Paying for account registration, aka storage deposit
-
-        contract.wrap(None);
+        // Without an amount, all the complete baskets are wrapped
+        contract.ft_on_transfer(accounts(1), 5.into(), json!({"action": "wrap"}).to_string());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 3 * WRAP_TO_UNDERLYING_RATIO);
         assert_eq!(
-            contract
-                .get_ft_balance_internal(&accounts(1).to_string(), &token_id.clone().to_string()),
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
             0
         );
+    }
 
-        testing_env!(context
-            .storage_usage(env::storage_usage())
-            .attached_deposit(1)
-            .predecessor_account_id(accounts(1))
-            .build());
-
-        assert_eq!(contract.ft_balance_of(accounts(1)).0, amount_transfer);
-        contract.ft_transfer(accounts(2), amount_transfer.into(), None);
+    #[test]
+    #[should_panic(
+        expected = "Expected the deposit of @bob to be credited to itself to run an action"
+    )]
+    fn test_deposit_action_for_other_account() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(
+            vec![TokenWithRatioValid {
+                token_id: token_id.clone(),
+                ratio: 2.into(),
+                denominator: None,
+            }],
+            SetConfig::default(),
+        );
+        register_user(&mut contract, &mut context, accounts(0));
 
         testing_env!(context
             .storage_usage(env::storage_usage())
-            .account_balance(env::account_balance())
-            .is_view(true)
             .attached_deposit(0)
+            .predecessor_account_id(token_id.clone())
             .build());
-        assert_eq!(contract.ft_balance_of(accounts(1)).0, (0));
-        assert_eq!(contract.ft_balance_of(accounts(2)).0, amount_transfer);
+        // Bob cannot wrap the internal balance of Alice through its own deposit
+        let msg = json!({"sender_id": accounts(0), "action": "wrap"}).to_string();
+        contract.ft_on_transfer(accounts(1), 2.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected charlie to be registered for internal balances")]
+    fn test_unwrap_to_unregistered() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 10);
+        contract.wrap(None, None);
+        contract.unwrap_to(accounts(2), WRAP_TO_UNDERLYING_RATIO.into(), None);
+    }
+
+    #[test]
+    fn test_fractional_ratio_rounding() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(
+            vec![TokenWithRatioValid {
+                token_id: token_id.clone(),
+                ratio: 3.into(),
+                denominator: Some(2.into()),
+            }],
+            SetConfig::default(),
+        );
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 10);
+        // 10 * 2 / 3 = 6 units can be wrapped, taking up 9 tokens
+        contract.wrap(None, None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 6 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            1
+        );
+
+        // Releasing 1 unit rounds 1.5 down
+        contract.unwrap(WRAP_TO_UNDERLYING_RATIO.into(), None);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            2
+        );
+    }
+
+    #[test]
+    fn test_wrap_round_down() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 10);
+        let wrapped = contract.wrap(Some((2 * WRAP_TO_UNDERLYING_RATIO + 5).into()), Some(true));
+        assert_eq!(wrapped.0, 2 * WRAP_TO_UNDERLYING_RATIO);
+
+        let unwrapped = contract.unwrap((WRAP_TO_UNDERLYING_RATIO + 5).into(), Some(true));
+        assert_eq!(unwrapped.0, WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, WRAP_TO_UNDERLYING_RATIO);
+
+        let backing = contract.set_backing();
+        assert_eq!(backing.total_supply.0, WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(backing.locked_backing[0].token_id, token_id.to_string());
+        assert_eq!(backing.locked_backing[0].amount.0, 1);
+    }
+
+    #[test]
+    fn test_unwrap_redeem_fee() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(
+            single_token_ratios(),
+            SetConfig {
+                // 2% owner redemption fee
+                owner_redeem_fee: Some(20_000_000_000_000.into()),
+                // 1% platform redemption fee
+                platform_redeem_fee: Some(10_000_000_000_000.into()),
+                ..Default::default()
+            },
+        );
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 100);
+        let amount = 100 * WRAP_TO_UNDERLYING_RATIO;
+        contract.wrap(None, None);
+
+        let preview = contract.preview_unwrap(amount.into(), None);
+        assert_eq!(preview.burned_amount.0, 97 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(preview.owner_fee_amount.0, 2 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(preview.platform_fee_amount.0, WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(preview.underlying[0].amount.0, 97);
+
+        let unwrapped = contract.unwrap(amount.into(), None);
+        assert_eq!(unwrapped.0, amount);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 2 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.ft_balance_of(accounts(4)).0, WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.ft_total_supply().0, 3 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            97
+        );
+    }
+
+    #[test]
+    fn test_unwrap_redeem_fee_round_down() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(
+            single_token_ratios(),
+            // 1% platform redemption fee
+            SetConfig {
+                platform_redeem_fee: Some(10_000_000_000_000.into()),
+                ..Default::default()
+            },
+        );
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 10);
+        contract.wrap(None, None);
+
+        // 9.9 units are left after the fee, so only 9 are burned and the rest stays
+        let amount = 10 * WRAP_TO_UNDERLYING_RATIO;
+        assert_eq!(contract.unwrap(amount.into(), Some(true)).0, amount);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 9 * WRAP_TO_UNDERLYING_RATIO / 10);
+        assert_eq!(contract.ft_balance_of(accounts(4)).0, WRAP_TO_UNDERLYING_RATIO / 10);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            9
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Expected 9900000 net of the redemption fee to be divisible by the wrap to underlying ratio"
+    )]
+    fn test_unwrap_redeem_fee_not_divisible() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(
+            single_token_ratios(),
+            // 1% platform redemption fee
+            SetConfig {
+                platform_redeem_fee: Some(10_000_000_000_000.into()),
+                ..Default::default()
+            },
+        );
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 10);
+        contract.wrap(None, None);
+
+        contract.unwrap((10 * WRAP_TO_UNDERLYING_RATIO).into(), None);
+    }
+
+    #[test]
+    fn test_redeem_fee_in_components_unregistered_owner() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(
+            single_token_ratios(),
+            SetConfig {
+                // 2% owner redemption fee
+                owner_redeem_fee: Some(20_000_000_000_000.into()),
+                // 1% platform redemption fee
+                platform_redeem_fee: Some(10_000_000_000_000.into()),
+                redeem_fee_in_components: Some(true),
+                ..Default::default()
+            },
+        );
+        // Only the platform is registered for internal balances
+        register_user(&mut contract, &mut context, accounts(4));
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 1_000);
+        contract.wrap(None, None);
+        testing_env!(context.storage_usage(env::storage_usage()).build());
+        contract.unwrap((1_000 * WRAP_TO_UNDERLYING_RATIO).into(), None);
+
+        // The owner takes its fee in set tokens, the platform in the released tokens
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 20 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(4).to_string(), &token_id.to_string()),
+            9
+        );
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            971
+        );
+        assert_eq!(contract.locked_backing_of(&token_id.to_string()), 20);
+
+        // The fees are charged, not minted
+        let events = get_events();
+        let names: Vec<&str> = events.iter().map(|e| e["event"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["ft_burn", "ft_transfer", "fee_charged", "set_unwrap"]);
+        let fee = &events[2]["data"];
+        assert_eq!(fee["kind"], "redeem");
+        assert_eq!(fee["owner_amount"], (20 * WRAP_TO_UNDERLYING_RATIO).to_string());
+        assert_eq!(fee["owner_components"], json!([]));
+        assert_eq!(fee["platform_amount"], "0");
+        assert_eq!(fee["platform_components"], json!([{ "token_id": token_id, "amount": "9" }]));
+    }
+
+    #[test]
+    fn test_ft_transfer_call_refund_events() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(3));
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 10);
+        contract.wrap(None, None);
+        let amount = 10 * WRAP_TO_UNDERLYING_RATIO;
+        testing_env!(context.storage_usage(env::storage_usage()).build());
+        contract.ft_transfer_call(accounts(3), amount.into(), None, "".to_string());
+        let events = get_events();
+        assert_eq!(events[0]["event"], "ft_transfer");
+        assert_eq!(events[0]["data"][0]["amount"], amount.to_string());
+
+        // The receiver only uses 7 and the reverse transfer brings the events to the net amount
+        testing_env!(
+            context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&U128(3 * WRAP_TO_UNDERLYING_RATIO)).unwrap()
+            )]
+        );
+        let used = contract.ft_resolve_transfer(accounts(1), accounts(3), amount.into());
+        assert_eq!(used.0, 7 * WRAP_TO_UNDERLYING_RATIO);
+        let events = get_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "ft_transfer");
+        assert_eq!(events[0]["data"][0]["old_owner_id"], accounts(3).to_string());
+        assert_eq!(events[0]["data"][0]["new_owner_id"], accounts(1).to_string());
+        assert_eq!(events[0]["data"][0]["amount"], (3 * WRAP_TO_UNDERLYING_RATIO).to_string());
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 7 * WRAP_TO_UNDERLYING_RATIO);
+    }
+
+    #[test]
+    fn test_streaming_fee() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let token_id = accounts(5);
+        let mut contract = new_set(
+            single_token_ratios(),
+            SetConfig {
+                // 0.1% of the supply per second for the owner
+                owner_streaming_fee: Some(1_000_000_000_000.into()),
+                ..Default::default()
+            },
+        );
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 100);
+        let amount = 100 * WRAP_TO_UNDERLYING_RATIO;
+        contract.wrap(None, None);
+
+        testing_env!(context.block_timestamp(10_000_000_000).build());
+        let accrued = contract.accrued_fees();
+        assert_eq!(accrued.owner_fee_amount.0, WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(accrued.platform_fee_amount.0, 0);
+        assert_eq!(accrued.seconds, 10);
+
+        contract.accrue_fees();
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.ft_total_supply().0, amount + WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.accrued_fees().owner_fee_amount.0, 0);
+
+        // The fee is paid for by the holders, so the set stays fully backed
+        contract.unwrap(amount.into(), None);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            99
+        );
+    }
+
+    #[test]
+    fn test_fee_change_delay() {
+        let (mut contract, mut context) = new_set_with_fee_changes();
+        contract.propose_fee_change(Some(20_000_000_000_000.into()), None, None);
+        let pending = contract.pending_fee_change().unwrap();
+        assert_eq!(pending.owner_fee.0, 20_000_000_000_000);
+        assert_eq!(pending.applicable_at.0, 3_600_000_000_000);
+        assert_eq!(contract.set_metadata().set_fee.owner_fee, 0);
+
+        testing_env!(context
+            .block_timestamp(3_600_000_000_000)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.apply_fee_change();
+        assert_eq!(contract.set_metadata().set_fee.owner_fee, 20_000_000_000_000);
+        assert!(contract.pending_fee_change().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "The fee change can only be applied from 3600000000000")]
+    fn test_fee_change_before_delay() {
+        let (mut contract, mut context) = new_set_with_fee_changes();
+        contract.propose_fee_change(Some(10_000_000_000_000.into()), None, None);
+        testing_env!(context.block_timestamp(3_599_000_000_000).build());
+        contract.apply_fee_change();
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a fee to increase by at most 20000000000000")]
+    fn test_fee_change_above_max_increase() {
+        let (mut contract, _) = new_set_with_fee_changes();
+        contract.propose_fee_change(Some(30_000_000_000_000.into()), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the fee change delay to be at most 31536000 seconds")]
+    fn test_fee_change_delay_too_long() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        new_set(
+            single_token_ratios(),
+            SetConfig { fee_change_delay: Some(u64::MAX), ..Default::default() },
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the fee change delay to be at least 3600 seconds, got 0")]
+    fn test_fee_change_delay_too_short() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        new_set(
+            single_token_ratios(),
+            SetConfig { fee_change_delay: Some(0), ..Default::default() },
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the recomposition notice to be at least 3600 seconds")]
+    fn test_recomposition_notice_too_short() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        new_set(
+            single_token_ratios(),
+            SetConfig { recomposition_notice: Some(0), ..Default::default() },
+        );
+    }
+
+    #[test]
+    fn test_ownership_transfer() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let token_id = accounts(5);
+        let mut contract = new_set_with_fees(
+            single_token_ratios(),
+            0.into(),
+            // 1% owner fee
+            10_000_000_000_000.into(),
+            None,
+            SetConfig::default(),
+        );
+        register_user(&mut contract, &mut context, accounts(1));
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.propose_owner(accounts(3), Some(60));
+        assert_eq!(contract.pending_owner().unwrap().account_id, accounts(3).to_string());
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_ownership();
+        assert_eq!(contract.get_owner_id(), accounts(3).to_string());
+        assert!(contract.pending_owner().is_none());
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.wrap(None, None);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+    }
+
+    #[test]
+    fn test_platform_fee_and_id_change() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let mut contract = new_set_with_fees(
+            single_token_ratios(),
+            // 1% platform fee
+            10_000_000_000_000.into(),
+            0.into(),
+            None,
+            SetConfig {
+                // Allow the platform to raise its fee up to 3%
+                max_platform_fee: Some(30_000_000_000_000.into()),
+                ..Default::default()
+            },
+        );
+        register_user(&mut contract, &mut context, accounts(3));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.propose_platform_fee(20_000_000_000_000.into());
+        assert_eq!(
+            contract.set_metadata().pending_platform_fee_change.unwrap().platform_fee.0,
+            20_000_000_000_000
+        );
+        testing_env!(context.block_timestamp(DEFAULT_FEE_CHANGE_DELAY * 1_000_000_000).build());
+        contract.apply_platform_fee_change();
+        assert_eq!(contract.set_metadata().set_fee.platform_fee, 20_000_000_000_000);
+
+        contract.propose_platform_id(accounts(3));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_platform_id();
+        let metadata = contract.set_metadata();
+        assert_eq!(metadata.set_fee.platform_id, accounts(3).to_string());
+        assert!(metadata.pending_platform_id.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the platform fee to be at most 10000000000000")]
+    fn test_platform_fee_above_max() {
+        let mut context = get_context(accounts(4));
+        testing_env!(context.build());
+        let mut contract = new_set_with_fees(
+            single_token_ratios(),
+            10_000_000_000_000.into(),
+            0.into(),
+            None,
+            SetConfig::default(),
+        );
+        testing_env!(context.attached_deposit(1).build());
+        contract.propose_platform_fee(20_000_000_000_000.into());
+    }
+
+    #[test]
+    fn test_pause_wrap_allows_unwrap() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_guardian(Some(accounts(3)));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.wrap(None, None);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.pause(Some(true), None, Some(true), None);
+        let status = contract.pause_status();
+        assert!(status.wrap && status.deposit);
+        assert!(!status.unwrap && !status.transfer);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.unwrap((100 * WRAP_TO_UNDERLYING_RATIO).into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.unpause(Some(true), None, Some(true), None);
+        assert_eq!(contract.pause_status(), PauseStatus::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrapping is paused")]
+    fn test_pause_wrap() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_guardian(Some(accounts(3)));
+        contract.pause(Some(true), None, None, None);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.wrap(None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Deposits are paused")]
+    fn test_pause_token_deposit() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.pause(None, None, Some(true), None);
+
+        testing_env!(context.attached_deposit(0).predecessor_account_id(token_id).build());
+        contract.ft_on_transfer(accounts(1), 100.into(), "".to_string());
+    }
+
+    #[test]
+    fn test_recomposition() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let token_id = accounts(5);
+        let new_token_id = accounts(3);
+        let mut contract = new_set(
+            single_token_ratios(),
+            SetConfig {
+                // A notice period of one hour
+                recomposition_notice: Some(MIN_DELAY),
+                ..Default::default()
+            },
+        );
+        fund_user(&mut contract, &mut context, accounts(2), &new_token_id, 200);
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 100);
+        let min_storage = contract.accounts.default_min_storage_bal;
+        contract.wrap(None, None);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.propose_recomposition(vec![
+            TokenWithRatioValid {
+                token_id: new_token_id.clone(),
+                ratio: 2.into(),
+                denominator: None,
+            },
+            TokenWithRatioValid {
+                token_id: accounts(0),
+                ratio: 1.into(),
+                denominator: Some(2.into()),
+            },
+        ]);
+        assert!(contract.set_metadata().pending_recomposition.is_some());
+
+        testing_env!(context.block_timestamp(3_600_000_000_000).build());
+        contract.increase_balance(&accounts(2).to_string(), &accounts(0).to_string(), 50);
+        contract.execute_recomposition();
+        assert_eq!(contract.set_metadata().set_ratios.len(), 2);
+        assert_eq!(
+            contract.accounts.default_min_storage_bal,
+            min_storage + contract.get_storage_cost_for_one_balance()
+        );
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(2).to_string(), &token_id.to_string()),
+            100
+        );
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(2).to_string(), &new_token_id.to_string()),
+            0
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.unwrap((100 * WRAP_TO_UNDERLYING_RATIO).into(), None);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &new_token_id.to_string()),
+            200
+        );
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &accounts(0).to_string()),
+            50
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Expected the owner to hold 200 of danny to back the recomposition, got 150"
+    )]
+    fn test_recomposition_not_funded() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let token_id = accounts(5);
+        let new_token_id = accounts(3);
+        let mut contract = new_set(
+            single_token_ratios(),
+            SetConfig { recomposition_notice: Some(MIN_DELAY), ..Default::default() },
+        );
+        // Short of the 200 the new ratio needs for the 100 units of the set
+        fund_user(&mut contract, &mut context, accounts(2), &new_token_id, 150);
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 100);
+        contract.wrap(None, None);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.propose_recomposition(vec![TokenWithRatioValid {
+            token_id: new_token_id,
+            ratio: 2.into(),
+            denominator: None,
+        }]);
+        testing_env!(context.block_timestamp(3_600_000_000_000).build());
+        contract.execute_recomposition();
+    }
+
+    #[test]
+    fn test_unwrap_during_auction() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let token_a = accounts(5);
+        let token_b = ValidAccountId::try_from("b-token").unwrap();
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        fund_user(&mut contract, &mut context, accounts(3), &token_b, 50);
+        fund_user(&mut contract, &mut context, accounts(1), &token_a, 100);
+        contract.wrap(None, None);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.start_auction(
+            token_a.clone(),
+            50.into(),
+            token_b.clone(),
+            100.into(),
+            50.into(),
+            60,
+        );
+        // Half of the lot is sold for 50 of b
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        assert_eq!(contract.bid(50.into(), None).0, 25);
+
+        // Half of the supply releases half of the backing of a and b and takes half of the
+        // rest of the lot, rounded up
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.unwrap((50 * WRAP_TO_UNDERLYING_RATIO).into(), None);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_a.to_string()),
+            37
+        );
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_b.to_string()),
+            25
+        );
+        assert_eq!(contract.locked_backing_of(&token_a.to_string()), 38);
+        let auction = contract.get_auction().unwrap().auction;
+        assert_eq!(auction.sell_amount.0, 37);
+        assert_eq!(auction.start_buy_amount.0, 74);
+        assert_eq!(auction.end_buy_amount.0, 37);
+    }
+
+    #[test]
+    fn test_bid_by_transfer() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let token_a = accounts(5);
+        let token_b = ValidAccountId::try_from("b-token").unwrap();
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(0));
+        fund_user(&mut contract, &mut context, accounts(1), &token_a, 100);
+        contract.wrap(None, None);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.start_auction(
+            token_a.clone(),
+            50.into(),
+            token_b.clone(),
+            100.into(),
+            50.into(),
+            60,
+        );
+
+        // Bob bids more than the whole lot is worth for Alice, the rest is refunded
+        testing_env!(context.attached_deposit(0).predecessor_account_id(token_b.clone()).build());
+        let msg = json!({"action": "bid", "receiver_id": accounts(0), "min_amount_out": "50"});
+        let unused = contract.ft_on_transfer(accounts(1), 150.into(), msg.to_string());
+        assert!(matches!(unused, PromiseOrValue::Value(U128(50))));
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(0).to_string(), &token_a.to_string()),
+            50
+        );
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_b.to_string()),
+            0
+        );
+        assert_eq!(contract.locked_backing_of(&token_b.to_string()), 100);
+        assert_eq!(contract.get_auction().unwrap().auction.bought.0, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a bid in b-token")]
+    fn test_bid_by_transfer_of_other_token() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let token_a = accounts(5);
+        let token_b = ValidAccountId::try_from("b-token").unwrap();
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        fund_user(&mut contract, &mut context, accounts(1), &token_a, 10);
+        contract.wrap(None, None);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.start_auction(token_a.clone(), 1.into(), token_b, 2.into(), 1.into(), 60);
+
+        testing_env!(context.attached_deposit(0).predecessor_account_id(token_a).build());
+        contract.ft_on_transfer(accounts(1), 2.into(), json!({"action": "bid"}).to_string());
+    }
+
+    #[test]
+    fn test_settle_auction_without_supply() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let token_a = accounts(5);
+        let token_b = ValidAccountId::try_from("b-token").unwrap();
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        fund_user(&mut contract, &mut context, accounts(1), &token_a, 10);
+        contract.wrap(None, None);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.start_auction(token_a.clone(), 5.into(), token_b, 10.into(), 5.into(), 60);
+
+        // The whole supply is unwrapped, so the ratios cannot be derived from the backing
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.unwrap((10 * WRAP_TO_UNDERLYING_RATIO).into(), None);
+        testing_env!(context.block_timestamp(60_000_000_000).build());
+        contract.settle_auction();
+        assert!(contract.get_auction().is_none());
+        let ratios = contract.set_metadata().set_ratios;
+        assert_eq!(ratios.len(), 1);
+        assert_eq!(ratios[0].token_id, token_a.to_string());
+        assert_eq!(ratios[0].ratio.0, 1);
+
+        // Wrapping works again with the old ratios
+        contract.wrap(None, None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 10 * WRAP_TO_UNDERLYING_RATIO);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the ratio of eugene to be a non-zero fraction")]
+    fn test_replace_ratios_zero_denominator() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        contract.replace_ratios(vec![TokenWithRatio {
+            token_id: accounts(4).into(),
+            ratio: 1.into(),
+            denominator: 0.into(),
+            decimals: None,
+        }]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the end buy amount to be at least 50")]
+    fn test_auction_above_max_discount() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        testing_env!(context.attached_deposit(1).build());
+        contract.start_auction(token_id, 1.into(), accounts(3), 100.into(), 1.into(), 60);
+    }
+
+    #[test]
+    fn test_burn_releases_whole_units() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 10);
+        contract.wrap(None, None);
+
+        // The fungible token burns the amount before calling back
+        let burned = 5 * WRAP_TO_UNDERLYING_RATIO / 2;
+        contract.token.internal_withdraw(&accounts(1).to_string(), burned);
+        contract.on_burn(accounts(1).to_string(), burned);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            2
+        );
+        assert_eq!(contract.locked_backing_of(&token_id.to_string()), 8);
+    }
+
+    #[test]
+    fn test_migrate_legacy_state() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let token_id = accounts(5).to_string();
+        let mut ratios = Vector::new(b"set-ratio".to_vec());
+        ratios.push(&LegacyTokenWithRatio { token_id: token_id.clone(), ratio: 2 });
+        let mut token = FungibleToken::new(b"a".to_vec());
+        token.internal_register_account(&accounts(1).to_string());
+        token.internal_deposit(&accounts(1).to_string(), 3 * WRAP_TO_UNDERLYING_RATIO);
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "YY".to_string(),
+            symbol: "YY".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        };
+        env::state_write(&LegacyContract {
+            owner_id: accounts(2).to_string(),
+            token,
+            metadata: LazyOption::new(b"m".to_vec(), Some(&metadata)),
+            accounts: Accounts::new(),
+            set_info: LegacySetInfo {
+                ratios,
+                fee: LegacyFeeReceiver {
+                    owner_fee: 0,
+                    platform_fee: 0,
+                    platform_id: accounts(4).to_string(),
+                    updatable: false,
+                },
+            },
+        });
+
+        let contract = Contract::migrate();
+        assert_eq!(contract.locked_backing_of(&token_id), 6);
+        assert_eq!(contract.set_metadata().set_ratios[0].ratio.0, 2);
+        assert_eq!(contract.set_metadata().set_ratios[0].denominator.0, 1);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 3 * WRAP_TO_UNDERLYING_RATIO);
+        assert!(contract.pause_status().wrap);
+        assert!(contract.pause_status().unwrap);
+        assert!(contract.pause_status().deposit);
+    }
+
+    #[test]
+    fn test_migrate_seeds_internal_totals() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let ratios = single_token_ratios();
+        let mut contract = new_set(ratios, SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        register_user(&mut contract, &mut context, accounts(3));
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+        contract.increase_balance(&accounts(3).to_string(), &token_id.to_string(), 50);
+        // A legacy set kept no totals
+        contract.internal_totals.remove(&token_id.to_string());
+        let mut ratios = Vector::new(b"set-ratio".to_vec());
+        ratios.push(&LegacyTokenWithRatio { token_id: token_id.to_string(), ratio: 1 });
+        env::state_write(&LegacyContract {
+            owner_id: accounts(2).to_string(),
+            token: FungibleToken::new(b"a".to_vec()),
+            metadata: contract.metadata,
+            accounts: contract.accounts,
+            set_info: LegacySetInfo {
+                ratios,
+                fee: LegacyFeeReceiver {
+                    owner_fee: 0,
+                    platform_fee: 0,
+                    platform_id: accounts(4).to_string(),
+                    updatable: false,
+                },
+            },
+        });
+
+        let mut contract = Contract::migrate();
+        assert_eq!(contract.internal_balance_total(token_id.clone()).0, 0);
+        assert!(!contract.seed_internal_totals(1));
+        assert_eq!(contract.internal_balance_total(token_id.clone()).0, 100);
+        assert!(contract.pause_status().wrap);
+        assert!(contract.seed_internal_totals(1));
+        assert_eq!(contract.internal_balance_total(token_id).0, 150);
+        assert!(!contract.pause_status().wrap);
+        assert!(!contract.pause_status().unwrap);
+        assert!(!contract.pause_status().deposit);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the internal balance totals to be seeded first")]
+    fn test_internal_balance_change_before_seeding() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let ratios = single_token_ratios();
+        let mut contract = new_set(ratios, SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        contract.internal_totals_seeding.set(&0);
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+    }
+
+    #[test]
+    fn test_reconcile_deficit_pauses() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 110);
+        contract.wrap(Some((100 * WRAP_TO_UNDERLYING_RATIO).into()), None);
+
+        // 100 are locked and 10 are in the internal balance, but only 105 are held
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(105)).unwrap())]
+        );
+        let report = contract.resolve_reconcile(vec![token_id.to_string()], true);
+        assert_eq!(report[0].expected.0, 110);
+        assert_eq!(report[0].deficit.0, 5);
+        assert_eq!(report[0].surplus.0, 0);
+        assert!(contract.pause_status().wrap);
+        assert!(contract.pause_status().deposit);
+    }
+
+    #[test]
+    fn test_internal_balance_total() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        register_user(&mut contract, &mut context, accounts(3));
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 110);
+        contract.increase_balance(&accounts(3).to_string(), &token_id.to_string(), 40);
+        assert_eq!(contract.internal_balance_total(token_id.clone()).0, 150);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.wrap(Some((100 * WRAP_TO_UNDERLYING_RATIO).into()), None);
+        assert_eq!(contract.internal_balance_total(token_id.clone()).0, 50);
+
+        contract.subtract_balance(&accounts(3).to_string(), &token_id.to_string(), 40);
+        assert_eq!(contract.internal_balance_total(token_id).0, 10);
+    }
+
+    #[test]
+    fn test_wrap_events() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set_with_fees(
+            single_token_ratios(),
+            0.into(),
+            // 1% owner fee
+            10_000_000_000_000.into(),
+            None,
+            SetConfig::default(),
+        );
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 100);
+        contract.wrap(None, None);
+
+        let events = get_events();
+        let names: Vec<&str> = events.iter().map(|e| e["event"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["ft_mint", "ft_mint", "fee_minted", "set_wrap"]);
+        assert_eq!(events[0]["standard"], "nep141");
+        assert_eq!(events[0]["data"][0]["owner_id"], accounts(1).to_string());
+        assert_eq!(events[0]["data"][0]["amount"], (99 * WRAP_TO_UNDERLYING_RATIO).to_string());
+        assert_eq!(events[2]["data"]["kind"], "mint");
+        assert_eq!(events[3]["standard"], "token_set");
+        assert_eq!(events[3]["data"]["underlying"][0]["amount"], "100");
+    }
+
+    #[test]
+    fn test_closed_account_escrow() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        register_user(&mut contract, &mut context, accounts(3));
+        register_user(&mut contract, &mut context, accounts(4));
+        for account in [accounts(1), accounts(3)].iter() {
+            contract.increase_balance(&account.to_string(), &token_id.to_string(), 10);
+            testing_env!(context
+                .storage_usage(env::storage_usage())
+                .attached_deposit(1)
+                .predecessor_account_id(account.clone())
+                .build());
+            contract.wrap(None, None);
+            contract.storage_unregister(Some(true));
+            // The escrow storage is paid from the storage refund
+            let charged = format!("Charged @{} ", account);
+            assert!(get_logs().iter().any(|log| log.starts_with(&charged)));
+        }
+        assert_eq!(contract.ft_total_supply().0, 0);
+        assert_eq!(contract.get_escrows(None, None).len(), 2);
+        assert_eq!(contract.escrow_total(&token_id.to_string()), 20);
+        let escrow = contract.get_escrow(accounts(1)).unwrap();
+        assert_eq!(escrow.amounts[0].amount.0, 10);
+        assert_eq!(contract.locked_backing_of(&token_id.to_string()), 0);
+
+        // The first account registers again and claims its escrow within the window
+        register_user(&mut contract, &mut context, accounts(1));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.claim_escrow();
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            10
+        );
+        assert_eq!(contract.escrow_total(&token_id.to_string()), 10);
+
+        // The escrow of the second account falls to the platform after the window
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .block_timestamp((DEFAULT_ESCROW_WINDOW + 1) * 1_000_000_000)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.release_expired_escrow(accounts(3));
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(4).to_string(), &token_id.to_string()),
+            10
+        );
+        assert!(contract.get_escrows(None, None).is_empty());
+        assert_eq!(contract.escrow_total(&token_id.to_string()), 0);
+    }
+
+    #[test]
+    fn test_unregister_without_set_tokens_escrows_nothing() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        assert!(contract.storage_unregister(None));
+        assert!(contract.get_escrow(accounts(1)).is_none());
+        assert!(get_logs().iter().all(|log| !log.starts_with("Charged")));
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the escrow window to be at most")]
+    fn test_escrow_window_too_long() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        new_set(
+            single_token_ratios(),
+            SetConfig { escrow_window: Some(MAX_DELAY + 1), ..Default::default() },
+        );
+    }
+
+    #[test]
+    fn test_flash_loan() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set_with_fees(
+            single_token_ratios(),
+            // 3% platform fee and 1% owner fee
+            30_000_000_000_000.into(),
+            10_000_000_000_000.into(),
+            None,
+            SetConfig::default(),
+        );
+        register_user(&mut contract, &mut context, accounts(1));
+        register_user(&mut contract, &mut context, accounts(2));
+        register_user(&mut contract, &mut context, accounts(3));
+        register_user(&mut contract, &mut context, accounts(4));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        // A 1% fee
+        contract.set_flash_loan_fee(Some(10_000_000_000_000.into()));
+        contract.add_flash_loan_borrower(accounts(3));
+        // Only the liquidity opted into the pool can be lent out
+        provide_flash_liquidity(&mut contract, &mut context, accounts(1), token_id.clone(), 1_000);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.flash_loan(token_id.clone(), 1_000.into(), accounts(3), "".to_string());
+        assert_eq!(contract.get_flash_loan().unwrap().amount.0, 1_000);
+
+        // The borrower deposits the loan with the fee from its `ft_on_transfer` and repays it
+        contract.increase_balance(&accounts(3).to_string(), &token_id.to_string(), 1_010);
+        contract.repay_flash_loan(1_010.into());
+        assert_eq!(contract.get_flash_loan().unwrap().repaid.0, 1_010);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(1_000)).unwrap())]
+        );
+        assert!(contract.resolve_flash_loan(10.into()));
+        assert!(contract.get_flash_loan().is_none());
+        // The fee is split 1 to 3 between the owner and the platform
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(2).to_string(), &token_id.to_string()),
+            3
+        );
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(4).to_string(), &token_id.to_string()),
+            7
+        );
+        assert_eq!(contract.get_flash_liquidity(accounts(1), token_id.clone()).0, 1_000);
+        assert!(!contract.pause_status().wrap);
+    }
+
+    #[test]
+    fn test_flash_loan_missing_fee_fails() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        register_user(&mut contract, &mut context, accounts(2));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_flash_loan_fee(Some(10_000_000_000_000.into()));
+        contract.add_flash_loan_borrower(accounts(3));
+        provide_flash_liquidity(&mut contract, &mut context, accounts(1), token_id.clone(), 1_000);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.flash_loan(token_id.clone(), 1_000.into(), accounts(3), "".to_string());
+
+        // The whole loan comes back as unused, but without the fee
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(0)).unwrap())]
+        );
+        assert!(!contract.resolve_flash_loan(10.into()));
+        assert!(contract.get_flash_loan().is_none());
+        assert_eq!(contract.get_flash_liquidity(accounts(1), token_id.clone()).0, 1_000);
+    }
+
+    #[test]
+    fn test_flash_loan_default_taken_from_pool() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(0));
+        register_user(&mut contract, &mut context, accounts(1));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_flash_loan_fee(Some(0.into()));
+        contract.add_flash_loan_borrower(accounts(3));
+        provide_flash_liquidity(&mut contract, &mut context, accounts(0), token_id.clone(), 300);
+        provide_flash_liquidity(&mut contract, &mut context, accounts(1), token_id.clone(), 700);
+        // A free internal balance which was not opted in
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 500);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.flash_loan(token_id.clone(), 1_000.into(), accounts(3), "".to_string());
+
+        // Only 600 of the loan come back
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(400)).unwrap())]
+        );
+        assert!(!contract.resolve_flash_loan(0.into()));
+        // The providers bear the loss in proportion to their shares
+        assert_eq!(contract.get_flash_loan_pool(token_id.clone()).unwrap().amount.0, 600);
+        assert_eq!(contract.get_flash_liquidity(accounts(0), token_id.clone()).0, 180);
+        assert_eq!(contract.get_flash_liquidity(accounts(1), token_id.clone()).0, 420);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            500
+        );
+
+        testing_env!(context.attached_deposit(1).predecessor_account_id(accounts(1)).build());
+        assert_eq!(contract.withdraw_flash_liquidity(token_id.clone(), None).0, 420);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            920
+        );
+        assert_eq!(contract.get_flash_liquidity(accounts(1), token_id).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "An underlying token of the set is lent out")]
+    fn test_flash_liquidity_locked_during_loan() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_flash_loan_fee(Some(0.into()));
+        contract.add_flash_loan_borrower(accounts(3));
+        provide_flash_liquidity(&mut contract, &mut context, accounts(1), token_id.clone(), 100);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.flash_loan(token_id.clone(), 100.into(), accounts(3), "".to_string());
+
+        // The provider cannot pull the lent tokens out before the loan resolves
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.withdraw_flash_liquidity(token_id, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected @danny to be an allowed flash loan borrower")]
+    fn test_flash_loan_not_allowed() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        testing_env!(context.attached_deposit(1).predecessor_account_id(accounts(2)).build());
+        contract.set_flash_loan_fee(Some(0.into()));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.flash_loan(token_id, 1_000.into(), accounts(3), "".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the amount to be at most the 100 of eugene in the flash")]
+    fn test_flash_loan_above_pool() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_flash_loan_fee(Some(0.into()));
+        contract.add_flash_loan_borrower(accounts(3));
+        // 10 of the 110 stay out of the pool
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 10);
+        provide_flash_liquidity(&mut contract, &mut context, accounts(1), token_id.clone(), 100);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.flash_loan(token_id, 110.into(), accounts(3), "".to_string());
+    }
+
+    #[test]
+    fn test_flash_mint() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(3));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        // A 1% fee
+        contract.set_flash_mint_fee(Some(10_000_000_000_000.into()));
+        let amount = 1_000 * WRAP_TO_UNDERLYING_RATIO;
+        let fee = 10 * WRAP_TO_UNDERLYING_RATIO;
+        contract.increase_balance(&accounts(3).to_string(), &token_id.to_string(), 1_000);
+        // The receiver already holds the fee
+        contract.token.internal_deposit(&accounts(3).to_string(), fee);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.flash_mint(amount.into(), accounts(3), "".to_string());
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, amount + fee);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(3).to_string(), &token_id.to_string()),
+            0
+        );
+        assert_eq!(contract.set_backing().locked_backing[0].amount.0, 1_000);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        assert!(contract.resolve_flash_mint());
+        assert!(contract.get_flash_mint().is_none());
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 0);
+        assert_eq!(contract.ft_total_supply().0, 0);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(3).to_string(), &token_id.to_string()),
+            1_000
+        );
+        assert!(contract.set_backing().locked_backing.is_empty());
+    }
+
+    #[test]
+    fn test_flash_mint_not_repaid() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        // A 1% wrapping fee each for the platform and the owner
+        let mut contract = new_set_with_fees(
+            single_token_ratios(),
+            10_000_000_000_000.into(),
+            10_000_000_000_000.into(),
+            None,
+            SetConfig::default(),
+        );
+        register_user(&mut contract, &mut context, accounts(1));
+        register_user(&mut contract, &mut context, accounts(3));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_flash_mint_fee(Some(0.into()));
+        let amount = 1_000 * WRAP_TO_UNDERLYING_RATIO;
+        // The collateral covers the amount and the wrapping fees on it
+        contract.increase_balance(&accounts(3).to_string(), &token_id.to_string(), 1_020);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.flash_mint(amount.into(), accounts(3), "".to_string());
+        assert_eq!(contract.set_backing().locked_backing[0].amount.0, 1_020);
+        // The receiver sends the minted tokens away instead of repaying them
+        contract.token.internal_transfer(
+            &accounts(3).to_string(),
+            &accounts(1).to_string(),
+            amount,
+            None,
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        assert!(!contract.resolve_flash_mint());
+        assert!(contract.get_flash_mint().is_none());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, amount);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 10 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.ft_balance_of(accounts(4)).0, 10 * WRAP_TO_UNDERLYING_RATIO);
+        let backing = contract.set_backing();
+        assert_eq!(backing.total_supply.0, 1_020 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(backing.locked_backing[0].amount.0, 1_020);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(3).to_string(), &token_id.to_string()),
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected the receiver of the flash mint to be the caller")]
+    fn test_flash_mint_to_other_receiver() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(3));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_flash_mint_fee(Some(0.into()));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.flash_mint(WRAP_TO_UNDERLYING_RATIO.into(), accounts(3), "".to_string());
+    }
+
+    #[test]
+    fn test_convert_to() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 12);
+        contract.wrap(Some((10 * WRAP_TO_UNDERLYING_RATIO).into()), None);
+        contract.convert_to(accounts(3), (4 * WRAP_TO_UNDERLYING_RATIO).into());
+
+        // The target set has the same composition, so all of the released tokens go to it
+        let target_metadata = contract.set_metadata();
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&target_metadata).unwrap()
+            )]
+        );
+        contract.resolve_convert_to(
+            accounts(1).to_string(),
+            accounts(3).to_string(),
+            (4 * WRAP_TO_UNDERLYING_RATIO).into(),
+        );
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 6 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.locked_backing_of(&token_id.to_string()), 6);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            2
+        );
+    }
+
+    #[test]
+    fn test_convert_to_two_components() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_a = accounts(5);
+        let token_b = ValidAccountId::try_from("b-token").unwrap();
+        let target_set = ValidAccountId::try_from("target-set").unwrap();
+        let mut contract = new_set(
+            vec![
+                TokenWithRatioValid {
+                    token_id: token_a.clone(),
+                    ratio: 1.into(),
+                    denominator: None,
+                },
+                TokenWithRatioValid {
+                    token_id: token_b.clone(),
+                    ratio: 2.into(),
+                    denominator: None,
+                },
+            ],
+            SetConfig::default(),
+        );
+        register_user(&mut contract, &mut context, accounts(1));
+        contract.increase_balance(&accounts(1).to_string(), &token_a.to_string(), 10);
+        contract.increase_balance(&accounts(1).to_string(), &token_b.to_string(), 20);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.wrap(None, None);
+        contract.convert_to(target_set.clone(), (4 * WRAP_TO_UNDERLYING_RATIO).into());
+
+        // The target set takes one of each token per unit
+        let mut target_metadata = contract.set_metadata();
+        target_metadata.set_ratios = vec![
+            TokenWithRatio {
+                token_id: token_a.to_string(),
+                ratio: 1.into(),
+                denominator: 1.into(),
+                decimals: None,
+            },
+            TokenWithRatio {
+                token_id: token_b.to_string(),
+                ratio: 1.into(),
+                denominator: 1.into(),
+                decimals: None,
+            },
+        ];
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&target_metadata).unwrap()
+            )]
+        );
+        contract.resolve_convert_to(
+            accounts(1).to_string(),
+            target_set.to_string(),
+            (4 * WRAP_TO_UNDERLYING_RATIO).into(),
+        );
+        // 4 of a and 8 of b are released, 4 of each are deposited and 4 of b are left
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 6 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_a.to_string()),
+            0
+        );
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_b.to_string()),
+            4
+        );
+
+        let deposits = vec![
+            TokenAmount { token_id: token_a.to_string(), amount: 4.into() },
+            TokenAmount { token_id: token_b.to_string(), amount: 4.into() },
+        ];
+        testing_env!(
+            context.build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![
+                PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(4)).unwrap()),
+                PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(4)).unwrap()),
+            ]
+        );
+        contract.resolve_convert_deposits(
+            accounts(1).to_string(),
+            target_set.to_string(),
+            deposits.clone(),
+            (4 * WRAP_TO_UNDERLYING_RATIO).into(),
+            (4 * WRAP_TO_UNDERLYING_RATIO).into(),
+        );
+
+        testing_env!(
+            context.build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&U128(4 * WRAP_TO_UNDERLYING_RATIO)).unwrap()
+            )]
+        );
+        match contract.resolve_convert_wrap(
+            accounts(1).to_string(),
+            target_set.to_string(),
+            deposits,
+            (4 * WRAP_TO_UNDERLYING_RATIO).into(),
+        ) {
+            PromiseOrValue::Value(wrapped) => assert_eq!(wrapped.0, 4 * WRAP_TO_UNDERLYING_RATIO),
+            _ => panic!("Expected the conversion to be done"),
+        }
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 6 * WRAP_TO_UNDERLYING_RATIO);
+    }
+
+    #[test]
+    fn test_convert_to_refused_rolls_back() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let target_set = accounts(3);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 12);
+        contract.wrap(Some((10 * WRAP_TO_UNDERLYING_RATIO).into()), None);
+        contract.convert_to(target_set.clone(), (4 * WRAP_TO_UNDERLYING_RATIO).into());
+
+        let target_metadata = contract.set_metadata();
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&target_metadata).unwrap()
+            )]
+        );
+        contract.resolve_convert_to(
+            accounts(1).to_string(),
+            target_set.to_string(),
+            (4 * WRAP_TO_UNDERLYING_RATIO).into(),
+        );
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 6 * WRAP_TO_UNDERLYING_RATIO);
+
+        // The deposit is accepted, but the target set refuses to wrap
+        let deposits = vec![TokenAmount { token_id: token_id.to_string(), amount: 4.into() }];
+        testing_env!(
+            context.build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.resolve_convert_wrap(
+            accounts(1).to_string(),
+            target_set.to_string(),
+            deposits.clone(),
+            (4 * WRAP_TO_UNDERLYING_RATIO).into(),
+        );
+
+        // The deposit is withdrawn back and the set tokens are restored
+        testing_env!(
+            context.build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        let restored = contract.resolve_convert_rollback(
+            accounts(1).to_string(),
+            target_set.to_string(),
+            deposits,
+            (4 * WRAP_TO_UNDERLYING_RATIO).into(),
+        );
+        assert_eq!(restored.0, 4 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 10 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.locked_backing_of(&token_id.to_string()), 10);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            2
+        );
+    }
+
+    #[test]
+    fn test_native_near_component() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let near_id = ValidAccountId::try_from(NATIVE_NEAR_ID).unwrap();
+        let mut contract = new_set(
+            vec![
+                TokenWithRatioValid { token_id: near_id, ratio: 1.into(), denominator: None },
+                TokenWithRatioValid {
+                    token_id: token_id.clone(),
+                    ratio: 2.into(),
+                    denominator: None,
+                },
+            ],
+            SetConfig::default(),
+        );
+        register_user(&mut contract, &mut context, accounts(1));
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 20);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.deposit_near(None);
+        let near_id = NATIVE_NEAR_ID.to_string();
+        assert_eq!(contract.get_ft_balance_internal(&accounts(1).to_string(), &near_id), 10);
+
+        testing_env!(context.attached_deposit(1).build());
+        contract.wrap(None, None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 10 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.locked_backing_of(&near_id), 10);
+
+        contract.unwrap((4 * WRAP_TO_UNDERLYING_RATIO).into(), None);
+        assert_eq!(contract.get_ft_balance_internal(&accounts(1).to_string(), &near_id), 4);
+        contract.withdraw_near(4.into());
+        assert_eq!(contract.get_ft_balance_internal(&accounts(1).to_string(), &near_id), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected at least 2 yoctoNEAR attached, one for each transfer")]
+    fn test_unwrap_and_withdraw_deposit_per_transfer() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = new_set(
+            vec![
+                TokenWithRatioValid { token_id: accounts(5), ratio: 1.into(), denominator: None },
+                TokenWithRatioValid { token_id: accounts(3), ratio: 1.into(), denominator: None },
+            ],
+            SetConfig::default(),
+        );
+        register_user(&mut contract, &mut context, accounts(1));
+        contract.increase_balance(&accounts(1).to_string(), &accounts(5).to_string(), 10);
+        contract.increase_balance(&accounts(1).to_string(), &accounts(3).to_string(), 10);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.wrap(None, None);
+        contract.unwrap_and_withdraw((10 * WRAP_TO_UNDERLYING_RATIO).into(), None, None);
+    }
+
+    #[test]
+    fn test_resolve_unwrap_withdraw_failed() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+
+        // The whole transfer is returned to the internal balance
+        testing_env!(
+            context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+        let transferred = contract.resolve_unwrap_withdraw(
+            accounts(1).to_string(),
+            token_id.to_string(),
+            10.into(),
+            false,
+        );
+        assert_eq!(transferred.0, 0);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            10
+        );
+    }
+
+    #[test]
+    fn test_resolve_unwrap_withdraw_partial_refund() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set(single_token_ratios(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+
+        // The receiver only used 3 of the 10 transferred with `ft_transfer_call`
+        testing_env!(
+            context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(3)).unwrap())]
+        );
+        let transferred = contract.resolve_unwrap_withdraw(
+            accounts(1).to_string(),
+            token_id.to_string(),
+            10.into(),
+            true,
+        );
+        assert_eq!(transferred.0, 3);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            7
+        );
+    }
+
+    #[test]
+    fn test_native_near_apart_from_storage_deposits() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let near_id = ValidAccountId::try_from(NATIVE_NEAR_ID).unwrap();
+        let mut contract = new_set(
+            vec![TokenWithRatioValid {
+                token_id: near_id.clone(),
+                ratio: 1.into(),
+                denominator: None,
+            }],
+            SetConfig::default(),
+        );
+        register_user(&mut contract, &mut context, accounts(1));
+        let storage_balance = contract.storage_balance_of(accounts(1)).unwrap().total.0;
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.deposit_near(None);
+        assert_eq!(contract.storage_balance_of(accounts(1)).unwrap().total.0, storage_balance);
+
+        // More storage deposits do not add to the native NEAR balance
+        let cost_for_account = contract.accounts_storage_balance_bounds().min.0;
+        testing_env!(context.attached_deposit(cost_for_account).build());
+        contract.accounts_storage_deposit(None, None);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &near_id.to_string()),
+            10
+        );
+        assert_eq!(contract.internal_balance_total(near_id.clone()).0, 10);
+
+        // Withdrawing the native NEAR leaves the storage deposits alone
+        testing_env!(context.attached_deposit(1).build());
+        contract.withdraw_near(10.into());
+        assert_eq!(contract.internal_balance_total(near_id).0, 0);
+        assert_eq!(contract.storage_balance_of(accounts(1)).unwrap().total.0, storage_balance);
+    }
+
+    #[test]
+    fn test_preview_wrap_and_unwrap() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = new_set_with_fees(
+            vec![TokenWithRatioValid {
+                token_id: token_id.clone(),
+                ratio: 2.into(),
+                denominator: None,
+            }],
+            // 1% platform fee
+            10_000_000_000_000.into(),
+            // 4% owner fee
+            40_000_000_000_000.into(),
+            None,
+            SetConfig::default(),
+        );
+        register_user(&mut contract, &mut context, accounts(1));
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 200);
+
+        let preview = contract.preview_wrap(accounts(1), None, None);
+        let amount = 100 * WRAP_TO_UNDERLYING_RATIO;
+        assert_eq!(preview.amount.0, amount);
+        assert_eq!(preview.max_amount.0, amount);
+        assert_eq!(preview.platform_fee_amount.0, amount / 100);
+        assert_eq!(preview.owner_fee_amount.0, amount * 4 / 100);
+        assert_eq!(preview.wrapper_amount.0, amount * 95 / 100);
+        assert_eq!(preview.underlying[0].amount.0, 200);
+        assert!(preview.divisible);
+
+        let preview = contract.preview_unwrap((WRAP_TO_UNDERLYING_RATIO + 1).into(), None);
+        assert!(!preview.divisible);
+        assert_eq!(preview.remainder_amount.0, 1);
+        assert_eq!(preview.underlying[0].amount.0, 2);
+    }
+
+    #[test]
+    fn test_preview_matches_with_pending_fees() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(0).build());
+        let token_id = accounts(5);
+        let mut contract = new_set_with_fees(
+            single_token_ratios(),
+            // 1% platform fee
+            10_000_000_000_000.into(),
+            0.into(),
+            None,
+            SetConfig {
+                // 1% platform redemption fee
+                platform_redeem_fee: Some(10_000_000_000_000.into()),
+                // 0.1% of the supply per second for the owner
+                owner_streaming_fee: Some(1_000_000_000_000.into()),
+                ..Default::default()
+            },
+        );
+        fund_user(&mut contract, &mut context, accounts(1), &token_id, 100);
+        contract.wrap(None, None);
+
+        // 1% of the supply accrues to the owner before the unwrap, scaling the ratio to 100/101
+        testing_env!(context.block_timestamp(10_000_000_000).build());
+        let amount = 10 * WRAP_TO_UNDERLYING_RATIO + WRAP_TO_UNDERLYING_RATIO / 2;
+        let preview = contract.preview_unwrap(amount.into(), Some(true));
+        let balance_before = contract.ft_balance_of(accounts(1)).0;
+        let platform_before = contract.ft_balance_of(accounts(4)).0;
+        let unwrapped = contract.unwrap(amount.into(), Some(true)).0;
+        assert_eq!(preview.amount.0, unwrapped);
+        assert_eq!(
+            balance_before - contract.ft_balance_of(accounts(1)).0,
+            unwrapped - preview.remainder_amount.0
+        );
+        assert_eq!(
+            contract.ft_balance_of(accounts(4)).0 - platform_before,
+            preview.platform_fee_amount.0
+        );
+        assert_eq!(preview.burned_amount.0, 9 * WRAP_TO_UNDERLYING_RATIO);
+        // 9 units at the accrued ratio release 8 of the token, not 9
+        assert_eq!(preview.underlying[0].amount.0, 8);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            preview.underlying[0].amount.0
+        );
+
+        // The wrap preview accrues the pending fees as well
+        testing_env!(context.block_timestamp(20_000_000_000).build());
+        let preview = contract.preview_wrap(accounts(1), None, None);
+        let balance_before = contract.ft_balance_of(accounts(1)).0;
+        let wrapped = contract.wrap(None, None).0;
+        assert_eq!(preview.amount.0, wrapped);
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0 - balance_before,
+            preview.wrapper_amount.0
+        );
+        assert_eq!(
+            8 - contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            preview.underlying[0].amount.0
+        );
+    }
+
+    #[test]
+    fn test_wrap_transfer() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let platform_id = accounts(4);
+        let token_id = accounts(5);
+        let mut contract = Contract::new_default_meta(
+            accounts(2).into(),
+            "YOUR MOM".to_string(),
+            "YOUR MOM".to_string(),
+            None,
+            single_token_ratios(),
+            0.into(),
+            platform_id,
+            0.into(),
+            None,
+            None,
+            None,
+        );
+
+        // Paying for account registration, aka storage deposit
+        register_user(&mut contract, &mut context, accounts(1));
+
+        let amount_transfer = 100;
+        contract.increase_balance(
+            &accounts(1).to_string(),
+            &token_id.clone().to_string(),
+            amount_transfer,
+        );
+
+        // Paying for account registration, aka storage deposit
+        register_user(&mut contract, &mut context, accounts(2));
+
+        // Paying for account registration, aka storage deposit
+        register_user(&mut contract, &mut context, accounts(4));
+
+        assert_eq!(
+            contract
+                .get_ft_balance_internal(&accounts(1).to_string(), &token_id.clone().to_string()),
+            amount_transfer
+        );
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        // Paying for account registration, aka storage deposit
+
+        contract.wrap(None, None);
+        assert_eq!(
+            contract
+                .get_ft_balance_internal(&accounts(1).to_string(), &token_id.clone().to_string()),
+            0
+        );
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, amount_transfer);
+        contract.ft_transfer(accounts(2), amount_transfer.into(), None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .account_balance(env::account_balance())
+            .is_view(true)
+            .attached_deposit(0)
+            .build());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, (0));
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, amount_transfer);
+    }
+
+    #[test]
+    fn test_hosted_set_wrap_and_unwrap() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        // A 1% wrapping fee for the platform, which the hosted sets take as well
+        let mut contract = new_set_with_fees(
+            single_token_ratios(),
+            10_000_000_000_000.into(),
+            0.into(),
+            None,
+            SetConfig::default(),
+        );
+        register_user(&mut contract, &mut context, accounts(1));
+        register_user(&mut contract, &mut context, accounts(3));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10u128.pow(24))
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.create_set(
+            "basket".to_string(),
+            vec![TokenWithRatioValid {
+                token_id: token_id.clone(),
+                ratio: 2.into(),
+                denominator: None,
+            }],
+            10_000_000_000_000.into(),
+            None,
+            None,
+        );
+        assert_eq!(contract.get_hosted_sets(None, None), vec!["basket".to_string()]);
+
+        // The hosted set wraps from the same internal balances as the set of the contract
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 20);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        let wrapped = contract.mt_wrap("basket".to_string(), None, None, None);
+        assert_eq!(wrapped.0, 10 * WRAP_TO_UNDERLYING_RATIO);
+        let basket = || "basket".to_string();
+        assert_eq!(contract.mt_balance_of(accounts(1), basket()).0, 9_800_000);
+        assert_eq!(contract.mt_balance_of(accounts(3), basket()).0, 100_000);
+        assert_eq!(contract.mt_balance_of(accounts(4), basket()).0, 100_000);
+        assert_eq!(contract.mt_supply(basket()).unwrap().0, 10 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(contract.ft_total_supply().0, 0);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            0
+        );
+        assert_eq!(contract.hosted_backing_of(&token_id.to_string()), 20);
+
+        contract.mt_transfer(accounts(3), basket(), 4_800_000.into(), None, None);
+        assert_eq!(contract.mt_balance_of(accounts(3), basket()).0, 4_900_000);
+        let released = contract.mt_unwrap(basket(), (5 * WRAP_TO_UNDERLYING_RATIO).into(), None);
+        assert_eq!(released[0].amount.0, 10);
+        assert_eq!(contract.mt_balance_of(accounts(1), basket()).0, 0);
+        assert_eq!(
+            contract.get_ft_balance_internal(&accounts(1).to_string(), &token_id.to_string()),
+            10
+        );
+        let hosted = contract.get_hosted_set(basket()).unwrap();
+        assert_eq!(hosted.total_supply.0, 5 * WRAP_TO_UNDERLYING_RATIO);
+        assert_eq!(hosted.locked_backing[0].amount.0, 10);
+        assert_eq!(contract.hosted_backing_of(&token_id.to_string()), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a new set id, basket is already hosted")]
+    fn test_create_set_existing_id() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let ratios = single_token_ratios();
+        let mut contract = new_set(ratios.clone(), SetConfig::default());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10u128.pow(24))
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.create_set("basket".to_string(), ratios.clone(), 0.into(), None, None);
+        contract.create_set("basket".to_string(), ratios, 0.into(), None, None);
+    }
+
+    #[test]
+    fn test_mt_transfer_call_refund() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let ratios = single_token_ratios();
+        let mut contract = new_set(ratios.clone(), SetConfig::default());
+        register_user(&mut contract, &mut context, accounts(1));
+        register_user(&mut contract, &mut context, accounts(3));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10u128.pow(24))
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.create_set("basket".to_string(), ratios, 0.into(), None, None);
+        contract.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 10);
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.mt_wrap("basket".to_string(), None, None, None);
+
+        let amount = 10 * WRAP_TO_UNDERLYING_RATIO;
+        contract.mt_transfer_call(
+            accounts(3),
+            "basket".to_string(),
+            amount.into(),
+            None,
+            None,
+            "".to_string(),
+        );
+        assert_eq!(contract.mt_balance_of(accounts(3), "basket".to_string()).0, amount);
+
+        // The receiver uses only 3 units and the rest is refunded
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&vec![U128(7 * WRAP_TO_UNDERLYING_RATIO)]).unwrap()
+            )]
+        );
+        let used = contract.mt_resolve_transfer(
+            vec![accounts(1).to_string()],
+            accounts(3).to_string(),
+            vec!["basket".to_string()],
+            vec![amount.into()],
+        );
+        assert_eq!(used, vec![U128(3 * WRAP_TO_UNDERLYING_RATIO)]);
+        assert_eq!(
+            contract.mt_balance_of(accounts(1), "basket".to_string()).0,
+            7 * WRAP_TO_UNDERLYING_RATIO
+        );
+        assert_eq!(
+            contract.mt_balance_of(accounts(3), "basket".to_string()).0,
+            3 * WRAP_TO_UNDERLYING_RATIO
+        );
     }
 }
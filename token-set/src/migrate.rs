@@ -0,0 +1,160 @@
+use std::convert::TryFrom;
+
+use near_account::Accounts;
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LazyOption, LookupMap, UnorderedMap, Vector};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId};
+use shared::{SetConfig, TokenWithRatioValid};
+
+use crate::account_info::{AccountInfo, INTERNAL_TOTALS_PREFIX, INTERNAL_TOTALS_SEEDING_KEY};
+use crate::pause::PauseStatus;
+use crate::token_set_info::DEFAULT_ESCROW_WINDOW;
+use crate::{Contract, ContractContract, FeeReceiver, SetInfo};
+
+/// The wrap to underlying ratio and decimals every set had before they could be configured
+const LEGACY_WRAP_TO_UNDERLYING_RATIO: u128 = 1_000_000;
+const LEGACY_DECIMALS: u8 = 24;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct LegacyTokenWithRatio {
+    pub(crate) token_id: AccountId,
+    pub(crate) ratio: u32,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct LegacyFeeReceiver {
+    pub(crate) owner_fee: u128,
+    pub(crate) platform_fee: u128,
+    pub(crate) platform_id: AccountId,
+    pub(crate) updatable: bool,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct LegacySetInfo {
+    pub(crate) ratios: Vector<LegacyTokenWithRatio>,
+    pub(crate) fee: LegacyFeeReceiver,
+}
+
+/// The state of a set deployed before the locked backing was tracked
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct LegacyContract {
+    pub(crate) owner_id: AccountId,
+    pub(crate) token: FungibleToken,
+    pub(crate) metadata: LazyOption<FungibleTokenMetadata>,
+    pub(crate) accounts: Accounts<AccountInfo>,
+    pub(crate) set_info: LegacySetInfo,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Migrate a set deployed before the locked backing was tracked, after deploying the new
+    /// code. The locked backing is seeded from the total supply, which every wrap minted one
+    /// to one for the underlying ratios. The running internal balance totals are summed up
+    /// over the accounts by `seed_internal_totals` afterwards, with wrapping, unwrapping and
+    /// deposits paused until then. Every other setting takes its default
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        if env::predecessor_account_id() != env::current_account_id() {
+            panic!("Method migrate is private");
+        }
+        let mut legacy: LegacyContract =
+            env::state_read().unwrap_or_else(|| panic!("There is no state to migrate"));
+
+        // The new ratios are stored under the same prefix
+        let legacy_ratios: Vec<LegacyTokenWithRatio> = legacy.set_info.ratios.iter().collect();
+        legacy.set_info.ratios.clear();
+        let set_ratios: Vec<TokenWithRatioValid> = legacy_ratios
+            .iter()
+            .map(|ratio| TokenWithRatioValid {
+                token_id: ValidAccountId::try_from(ratio.token_id.clone()).unwrap(),
+                ratio: U128(ratio.ratio as u128),
+                denominator: None,
+            })
+            .collect();
+        let fee = FeeReceiver {
+            owner_fee: legacy.set_info.fee.owner_fee,
+            platform_fee: legacy.set_info.fee.platform_fee,
+            max_platform_fee: legacy.set_info.fee.platform_fee,
+            owner_redeem_fee: 0,
+            platform_redeem_fee: 0,
+            redeem_fee_in_components: false,
+            owner_streaming_fee: 0,
+            platform_streaming_fee: 0,
+            platform_id: legacy.set_info.fee.platform_id,
+            updatable: legacy.set_info.fee.updatable,
+        };
+        let config = SetConfig {
+            wrap_to_underlying_ratio: Some(U128(LEGACY_WRAP_TO_UNDERLYING_RATIO)),
+            ..Default::default()
+        };
+
+        let mut this = Self {
+            owner_id: legacy.owner_id,
+            token: legacy.token,
+            metadata: legacy.metadata,
+            accounts: legacy.accounts,
+            set_info: SetInfo::new(b"", set_ratios, fee, LEGACY_DECIMALS, &config),
+            pending_owner: None,
+            guardian_id: None,
+            paused: PauseStatus::default(),
+            escrows: UnorderedMap::new(b"escrow".to_vec()),
+            escrow_totals: LookupMap::new(b"escrow-totals".to_vec()),
+            escrow_window: DEFAULT_ESCROW_WINDOW,
+            hosted_sets: UnorderedMap::new(b"hosted-sets".to_vec()),
+            hosted_backing: LookupMap::new(b"hosted-backing".to_vec()),
+            internal_totals: LookupMap::new(INTERNAL_TOTALS_PREFIX),
+            internal_totals_seeding: LazyOption::new(INTERNAL_TOTALS_SEEDING_KEY, Some(&0)),
+        };
+        this.paused.wrap = true;
+        this.paused.unwrap = true;
+        this.paused.deposit = true;
+
+        let units = this.token.total_supply / LEGACY_WRAP_TO_UNDERLYING_RATIO;
+        for ratio in legacy_ratios.iter() {
+            this.lock_backing(&ratio.token_id, ratio.ratio as u128 * units);
+        }
+        log!("Migrated the set with {} units of backing", units);
+        this
+    }
+
+    /// Add the internal balances of the next `limit` accounts to the running totals of a
+    /// migrated set, in as many calls as the gas limit requires. Only the owner can call this.
+    /// Once every account is added, wrapping, unwrapping and deposits are unpaused.
+    ///
+    /// Internal balances cannot change until then. Accounts closed meanwhile reorder the
+    /// accounts, so they should be left open
+    ///
+    /// return whether every account is added
+    #[payable]
+    pub fn seed_internal_totals(&mut self, limit: u64) -> bool {
+        assert_one_yocto();
+        self.assert_owner();
+        let from_index = self
+            .internal_totals_seeding
+            .get()
+            .unwrap_or_else(|| panic!("The internal balance totals are already seeded"));
+        let accounts = self.accounts.accounts.values_as_vector();
+        let to_index = std::cmp::min(from_index + limit, accounts.len());
+        for index in from_index..to_index {
+            let account = accounts.get(index).unwrap();
+            for (token_id, balance) in account.info.internal_balance.iter() {
+                let total = self.internal_totals.get(&token_id).unwrap_or(0) + balance;
+                self.internal_totals.insert(&token_id, &total);
+            }
+        }
+        if to_index < accounts.len() {
+            self.internal_totals_seeding.set(&to_index);
+            log!("Seeded the internal balance totals of {} accounts", to_index);
+            return false;
+        }
+        self.internal_totals_seeding.remove();
+        self.paused.wrap = false;
+        self.paused.unwrap = false;
+        self.paused.deposit = false;
+        log!("Seeded the internal balance totals of all {} accounts", to_index);
+        true
+    }
+}
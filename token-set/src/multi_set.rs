@@ -0,0 +1,582 @@
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, log, near_bindgen, serde_json, AccountId, Balance, Promise,
+    PromiseOrValue, PromiseResult,
+};
+use shared::{SetConfig, TokenWithRatio, TokenWithRatioValid};
+
+use crate::events::{emit_mt_burn, emit_mt_mint, emit_mt_transfer};
+use crate::external::{
+    ext_mt_receiver, ext_self, GAS_FOR_MT_TRANSFER_CALL, GAS_FOR_RESOLVE_MT_TRANSFER,
+};
+use crate::preview::to_token_amounts;
+use crate::token_set_info::{Pricing, DEFAULT_DECIMALS};
+use crate::{Contract, ContractContract, FeeReceiver, SetInfo, TokenAmount};
+
+/// The maximum length of the id of a hosted set
+pub const MAX_SET_ID_LEN: usize = 64;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+/// A set hosted by the contract next to its own set. Its tokens are the NEP-245 multi-token
+/// with the set id as token id, and it wraps from the same internal balances as the set of
+/// the contract
+pub struct HostedSet {
+    owner_id: AccountId,
+    info: SetInfo,
+    total_supply: Balance,
+    balances: LookupMap<AccountId, Balance>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HostedSetView {
+    pub set_id: String,
+    pub owner_id: AccountId,
+    pub ratios: Vec<TokenWithRatio>,
+    pub fee: FeeReceiver,
+    pub decimals: u8,
+    pub wrap_to_underlying_ratio: U128,
+    pub total_supply: U128,
+    /// The amount of each token locked as backing of the total supply
+    pub locked_backing: Vec<TokenAmount>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+/// A NEP-245 token, which is fungible and so has no owner
+pub struct MultiToken {
+    pub token_id: String,
+    pub owner_id: Option<AccountId>,
+}
+
+impl HostedSet {
+    fn pricing(&self) -> Pricing {
+        Pricing {
+            ratios: self.info.ratios.to_vec(),
+            total_supply: self.total_supply,
+            wrap_to_underlying_ratio: self.info.wrap_to_underlying_ratio,
+        }
+    }
+
+    fn balance_of(&self, account_id: &AccountId) -> Balance {
+        self.balances.get(account_id).unwrap_or(0)
+    }
+
+    fn deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        let balance = self
+            .balance_of(account_id)
+            .checked_add(amount)
+            .unwrap_or_else(|| panic!("Balance overflow"));
+        self.balances.insert(account_id, &balance);
+        self.total_supply = self
+            .total_supply
+            .checked_add(amount)
+            .unwrap_or_else(|| panic!("Total supply overflow"));
+    }
+
+    fn withdraw(&mut self, account_id: &AccountId, amount: Balance) {
+        let balance = self
+            .balance_of(account_id)
+            .checked_sub(amount)
+            .unwrap_or_else(|| panic!("The account doesn't have enough balance"));
+        if balance == 0 {
+            self.balances.remove(account_id);
+        } else {
+            self.balances.insert(account_id, &balance);
+        }
+        self.total_supply -= amount;
+    }
+}
+
+/// The storage prefix of the collections of a hosted set. Set ids cannot contain `/`, so
+/// the prefixes of two sets never overlap
+fn hosted_set_prefix(set_id: &str) -> Vec<u8> {
+    format!("hosted/{}/", set_id).into_bytes()
+}
+
+fn assert_valid_set_id(set_id: &str) {
+    let valid_char = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+    if set_id.is_empty() || set_id.len() > MAX_SET_ID_LEN || !set_id.chars().all(valid_char) {
+        panic!(
+            "Expected the set id to be 1 to {} lowercase letters, digits, `-` or `_`",
+            MAX_SET_ID_LEN
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Create a set with the id `set_id` hosted by this contract, owned by the caller. It takes
+    /// the wrapping fee of the platform of the contract and `owner_fee` for the caller, and has
+    /// no redemption or streaming fees. Its tokens are the NEP-245 token `set_id`.
+    ///
+    /// The attached deposit pays for the storage of the set and the rest is refunded
+    #[payable]
+    pub fn create_set(
+        &mut self,
+        set_id: String,
+        set_ratios: Vec<TokenWithRatioValid>,
+        owner_fee: U128,
+        decimals: Option<u8>,
+        wrap_to_underlying_ratio: Option<U128>,
+    ) {
+        assert_valid_set_id(&set_id);
+        if self.hosted_sets.get(&set_id).is_some() {
+            panic!("Expected a new set id, {} is already hosted", set_id);
+        }
+        let owner_id = env::predecessor_account_id();
+        let initial_storage_usage = env::storage_usage();
+
+        let fee = FeeReceiver {
+            owner_fee: owner_fee.0,
+            platform_fee: self.set_info.fee.platform_fee,
+            max_platform_fee: self.set_info.fee.platform_fee,
+            owner_redeem_fee: 0,
+            platform_redeem_fee: 0,
+            redeem_fee_in_components: false,
+            owner_streaming_fee: 0,
+            platform_streaming_fee: 0,
+            platform_id: self.set_info.fee.platform_id.clone(),
+            updatable: false,
+        };
+        let config = SetConfig { decimals, wrap_to_underlying_ratio, ..Default::default() };
+        let prefix = hosted_set_prefix(&set_id);
+        let set = HostedSet {
+            owner_id: owner_id.clone(),
+            info: SetInfo::new(
+                &prefix,
+                set_ratios,
+                fee,
+                decimals.unwrap_or(DEFAULT_DECIMALS),
+                &config,
+            ),
+            total_supply: 0,
+            balances: LookupMap::new([prefix, b"balances".to_vec()].concat()),
+        };
+        self.hosted_sets.insert(&set_id, &set);
+        log!("@{} created the hosted set {}", owner_id, set_id);
+
+        let storage_cost =
+            Balance::from(env::storage_usage().saturating_sub(initial_storage_usage))
+                * env::storage_byte_cost();
+        let deposit = env::attached_deposit();
+        if deposit < storage_cost {
+            panic!("Expected at least {} yoctoNEAR attached for the storage", storage_cost);
+        }
+        if deposit > storage_cost {
+            Promise::new(owner_id).transfer(deposit - storage_cost);
+        }
+    }
+
+    /// Wrap the caller's internal balances into `amount` tokens of the hosted set `set_id`
+    /// (defaults to the maximum) minted to `receiver_id` (defaults to the caller). If
+    /// `round_down` is set, the amount is rounded down to a multiple of the wrap to underlying
+    /// ratio instead of failing
+    ///
+    /// return the amount of set tokens wrapped
+    #[payable]
+    pub fn mt_wrap(
+        &mut self,
+        set_id: String,
+        amount: Option<U128>,
+        receiver_id: Option<ValidAccountId>,
+        round_down: Option<bool>,
+    ) -> U128 {
+        assert_one_yocto();
+        self.paused.assert_wrap_active();
+        let account_id = env::predecessor_account_id();
+        let receiver_id: AccountId =
+            receiver_id.map(|r| r.into()).unwrap_or_else(|| account_id.clone());
+        self.assert_internal_account_registered(&receiver_id);
+        let mut set = self.hosted_set(&set_id);
+        let pricing = set.pricing();
+        let wrap_to_underlying_ratio = pricing.wrap_to_underlying_ratio;
+
+        let max_amount = self.get_max_amount(&pricing, &account_id);
+        let amount = amount.map(|a| a.0).unwrap_or(max_amount);
+        let amount = if round_down.unwrap_or(false) {
+            amount - amount % wrap_to_underlying_ratio
+        } else {
+            amount
+        };
+        if amount > max_amount {
+            panic!(
+                "Maximum amount that can be wrapped is {}, tried wrapping {}",
+                max_amount, amount
+            );
+        } else if amount == 0 || amount % wrap_to_underlying_ratio != 0 {
+            panic!(
+                "Expected {} to be a non-zero multiple of the wrap to underlying ratio of {}",
+                amount, wrap_to_underlying_ratio
+            );
+        }
+
+        for (token_id, consumed) in pricing.consumed_amounts(amount / wrap_to_underlying_ratio) {
+            self.subtract_balance(&account_id, &token_id, consumed);
+            self.lock_hosted_backing(&mut set, &token_id, consumed);
+        }
+        let (amount_receiver, owner_fee, platform_fee) = set.info.fee.mint_fee_split(amount);
+        let platform_id = set.info.fee.platform_id.clone();
+        let owner_id = set.owner_id.clone();
+        set.deposit(&receiver_id, amount_receiver);
+        set.deposit(&owner_id, owner_fee);
+        set.deposit(&platform_id, platform_fee);
+        self.hosted_sets.insert(&set_id, &set);
+
+        emit_mt_mint(&receiver_id, &set_id, amount_receiver, None);
+        emit_mt_mint(&owner_id, &set_id, owner_fee, Some("mint fee"));
+        emit_mt_mint(&platform_id, &set_id, platform_fee, Some("mint fee"));
+        amount.into()
+    }
+
+    /// Burn `amount` of the caller's tokens of the hosted set `set_id` and credit the
+    /// underlying tokens to the internal balance of `receiver_id` (defaults to the caller).
+    /// The amount has to be divisible by the wrap to underlying ratio
+    ///
+    /// return the underlying tokens released
+    #[payable]
+    pub fn mt_unwrap(
+        &mut self,
+        set_id: String,
+        amount: U128,
+        receiver_id: Option<ValidAccountId>,
+    ) -> Vec<TokenAmount> {
+        assert_one_yocto();
+        self.paused.assert_unwrap_active();
+        let account_id = env::predecessor_account_id();
+        let receiver_id: AccountId =
+            receiver_id.map(|r| r.into()).unwrap_or_else(|| account_id.clone());
+        self.assert_internal_account_registered(&receiver_id);
+        let mut set = self.hosted_set(&set_id);
+        let wrap_to_underlying_ratio = set.info.wrap_to_underlying_ratio;
+        let amount: Balance = amount.into();
+        if amount == 0 || amount % wrap_to_underlying_ratio != 0 {
+            panic!(
+                "Expected {} to be a non-zero multiple of the wrap to underlying ratio of {}",
+                amount, wrap_to_underlying_ratio
+            );
+        }
+
+        let released = set.pricing().released_amounts(amount / wrap_to_underlying_ratio);
+        set.withdraw(&account_id, amount);
+        let released: Vec<(AccountId, Balance)> = released
+            .into_iter()
+            .map(|(token_id, amount)| {
+                let amount =
+                    std::cmp::min(amount, set.info.locked_backing.get(&token_id).unwrap_or(0));
+                self.release_hosted_backing(&mut set, &token_id, amount);
+                self.increase_balance(&receiver_id, &token_id, amount);
+                (token_id, amount)
+            })
+            .collect();
+        self.hosted_sets.insert(&set_id, &set);
+
+        emit_mt_burn(&account_id, &set_id, amount, None);
+        to_token_amounts(released)
+    }
+
+    #[payable]
+    pub fn mt_transfer(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: String,
+        amount: U128,
+        approval: Option<(AccountId, u64)>,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        assert_no_approvals(approval.is_some());
+        let sender_id = env::predecessor_account_id();
+        self.internal_mt_batch_transfer(
+            &sender_id,
+            receiver_id.as_ref(),
+            &[token_id],
+            &[amount],
+            memo.as_deref(),
+        );
+    }
+
+    #[payable]
+    pub fn mt_batch_transfer(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_ids: Vec<String>,
+        amounts: Vec<U128>,
+        approvals: Option<Vec<Option<(AccountId, u64)>>>,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        assert_no_approvals(approvals.is_some());
+        let sender_id = env::predecessor_account_id();
+        self.internal_mt_batch_transfer(
+            &sender_id,
+            receiver_id.as_ref(),
+            &token_ids,
+            &amounts,
+            memo.as_deref(),
+        );
+    }
+
+    #[payable]
+    pub fn mt_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: String,
+        amount: U128,
+        approval: Option<(AccountId, u64)>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<Vec<U128>> {
+        assert_one_yocto();
+        assert_no_approvals(approval.is_some());
+        self.internal_mt_batch_transfer_call(
+            receiver_id.into(),
+            vec![token_id],
+            vec![amount],
+            memo,
+            msg,
+        )
+    }
+
+    #[payable]
+    pub fn mt_batch_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_ids: Vec<String>,
+        amounts: Vec<U128>,
+        approvals: Option<Vec<Option<(AccountId, u64)>>>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<Vec<U128>> {
+        assert_one_yocto();
+        assert_no_approvals(approvals.is_some());
+        self.internal_mt_batch_transfer_call(receiver_id.into(), token_ids, amounts, memo, msg)
+    }
+
+    /// Refund the amounts the receiver did not use, as far as it still holds them. A failed
+    /// `mt_on_transfer` refunds everything
+    ///
+    /// return the amounts used by the receiver
+    #[private]
+    pub fn mt_resolve_transfer(
+        &mut self,
+        previous_owner_ids: Vec<AccountId>,
+        receiver_id: AccountId,
+        token_ids: Vec<String>,
+        amounts: Vec<U128>,
+    ) -> Vec<U128> {
+        let unused: Vec<Balance> = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => match serde_json::from_slice::<Vec<U128>>(&value) {
+                Ok(unused) if unused.len() == amounts.len() => {
+                    unused.iter().zip(amounts.iter()).map(|(u, a)| u.0.min(a.0)).collect()
+                }
+                _ => amounts.iter().map(|a| a.0).collect(),
+            },
+            PromiseResult::Failed => amounts.iter().map(|a| a.0).collect(),
+        };
+
+        let sender_id = &previous_owner_ids[0];
+        let mut refunded_ids = vec![];
+        let mut refunded_amounts = vec![];
+        let used = token_ids
+            .iter()
+            .zip(amounts.iter())
+            .zip(unused.into_iter())
+            .map(|((token_id, amount), unused)| {
+                let mut set = self.hosted_set(token_id);
+                let refund = unused.min(set.balance_of(&receiver_id));
+                if refund > 0 {
+                    set.withdraw(&receiver_id, refund);
+                    set.deposit(sender_id, refund);
+                    self.hosted_sets.insert(token_id, &set);
+                    refunded_ids.push(token_id.clone());
+                    refunded_amounts.push(U128(refund));
+                }
+                U128(amount.0 - refund)
+            })
+            .collect();
+        if !refunded_ids.is_empty() {
+            emit_mt_transfer(
+                &receiver_id,
+                sender_id,
+                &refunded_ids,
+                &refunded_amounts,
+                Some("refund"),
+            );
+        }
+        used
+    }
+
+    pub fn mt_balance_of(&self, account_id: ValidAccountId, token_id: String) -> U128 {
+        self.hosted_sets
+            .get(&token_id)
+            .map(|set| set.balance_of(account_id.as_ref()))
+            .unwrap_or(0)
+            .into()
+    }
+
+    pub fn mt_batch_balance_of(
+        &self,
+        account_id: ValidAccountId,
+        token_ids: Vec<String>,
+    ) -> Vec<U128> {
+        token_ids
+            .into_iter()
+            .map(|token_id| self.mt_balance_of(account_id.clone(), token_id))
+            .collect()
+    }
+
+    pub fn mt_supply(&self, token_id: String) -> Option<U128> {
+        self.hosted_sets.get(&token_id).map(|set| set.total_supply.into())
+    }
+
+    pub fn mt_batch_supply(&self, token_ids: Vec<String>) -> Vec<Option<U128>> {
+        token_ids.into_iter().map(|token_id| self.mt_supply(token_id)).collect()
+    }
+
+    pub fn mt_token(&self, token_ids: Vec<String>) -> Vec<Option<MultiToken>> {
+        token_ids
+            .into_iter()
+            .map(|token_id| {
+                self.hosted_sets.get(&token_id).map(|_| MultiToken { token_id, owner_id: None })
+            })
+            .collect()
+    }
+
+    pub fn get_hosted_set(&self, set_id: String) -> Option<HostedSetView> {
+        self.hosted_sets.get(&set_id).map(|set| HostedSetView {
+            set_id,
+            owner_id: set.owner_id,
+            ratios: set.info.ratios.to_vec(),
+            fee: set.info.fee.clone(),
+            decimals: set.info.decimals,
+            wrap_to_underlying_ratio: set.info.wrap_to_underlying_ratio.into(),
+            total_supply: set.total_supply.into(),
+            locked_backing: to_token_amounts(set.info.locked_backing.to_vec()),
+        })
+    }
+
+    /// The ids of the hosted sets, paginated
+    pub fn get_hosted_sets(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<String> {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(self.hosted_sets.len());
+        self.hosted_sets.keys().skip(from_index as usize).take(limit as usize).collect()
+    }
+}
+
+fn assert_no_approvals(approvals: bool) {
+    if approvals {
+        panic!("Approvals are not supported by the hosted sets");
+    }
+}
+
+impl Contract {
+    fn hosted_set(&self, set_id: &str) -> HostedSet {
+        self.hosted_sets
+            .get(&set_id.to_string())
+            .unwrap_or_else(|| panic!("Expected {} to be a hosted set", set_id))
+    }
+
+    /// The amount of `token_id` locked as backing of all the hosted sets
+    pub(crate) fn hosted_backing_of(&self, token_id: &AccountId) -> Balance {
+        self.hosted_backing.get(token_id).unwrap_or(0)
+    }
+
+    fn lock_hosted_backing(&mut self, set: &mut HostedSet, token_id: &AccountId, amount: Balance) {
+        let locked = set.info.locked_backing.get(token_id).unwrap_or(0) + amount;
+        set.info.locked_backing.insert(token_id, &locked);
+        self.hosted_backing.insert(token_id, &(self.hosted_backing_of(token_id) + amount));
+    }
+
+    fn release_hosted_backing(
+        &mut self,
+        set: &mut HostedSet,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        let locked = set.info.locked_backing.get(token_id).unwrap_or(0) - amount;
+        if locked == 0 {
+            set.info.locked_backing.remove(token_id);
+        } else {
+            set.info.locked_backing.insert(token_id, &locked);
+        }
+        let total = self.hosted_backing_of(token_id) - amount;
+        if total == 0 {
+            self.hosted_backing.remove(token_id);
+        } else {
+            self.hosted_backing.insert(token_id, &total);
+        }
+    }
+
+    fn internal_mt_batch_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        token_ids: &[String],
+        amounts: &[U128],
+        memo: Option<&str>,
+    ) {
+        self.paused.assert_transfer_active();
+        if sender_id == receiver_id {
+            panic!("Sender and receiver should be different");
+        }
+        if token_ids.is_empty() || token_ids.len() != amounts.len() {
+            panic!("Expected as many amounts as token ids, and at least one");
+        }
+        self.assert_internal_account_registered(receiver_id);
+        for (token_id, amount) in token_ids.iter().zip(amounts.iter()) {
+            if amount.0 == 0 {
+                panic!("The amount should be a positive number");
+            }
+            let mut set = self.hosted_set(token_id);
+            set.withdraw(sender_id, amount.0);
+            set.deposit(receiver_id, amount.0);
+            self.hosted_sets.insert(token_id, &set);
+        }
+        emit_mt_transfer(sender_id, receiver_id, token_ids, amounts, memo);
+    }
+
+    fn internal_mt_batch_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        token_ids: Vec<String>,
+        amounts: Vec<U128>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<Vec<U128>> {
+        let sender_id = env::predecessor_account_id();
+        self.internal_mt_batch_transfer(
+            &sender_id,
+            &receiver_id,
+            &token_ids,
+            &amounts,
+            memo.as_deref(),
+        );
+        let previous_owner_ids = vec![sender_id.clone(); token_ids.len()];
+        ext_mt_receiver::mt_on_transfer(
+            sender_id,
+            previous_owner_ids.clone(),
+            token_ids.clone(),
+            amounts.clone(),
+            msg,
+            &receiver_id,
+            0,
+            env::prepaid_gas() - GAS_FOR_MT_TRANSFER_CALL,
+        )
+        .then(ext_self::mt_resolve_transfer(
+            previous_owner_ids,
+            receiver_id,
+            token_ids,
+            amounts,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_MT_TRANSFER,
+        ))
+        .into()
+    }
+}
@@ -0,0 +1,67 @@
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId, Balance, Promise};
+
+use crate::external::{ext_self, GAS_FOR_RESOLVE_TRANSFER};
+use crate::{Contract, ContractContract};
+
+/// The reserved component id standing for native NEAR instead of a NEP-141 token contract.
+/// Only `system` could create accounts under `system`, and the protocol never lets it sign a
+/// transaction, so no token contract or depositor can ever have this id
+pub const NATIVE_NEAR_ID: &str = "native.system";
+pub(crate) const NEAR_DECIMALS: u8 = 24;
+
+pub(crate) fn is_native_near(token_id: &str) -> bool {
+    token_id == NATIVE_NEAR_ID
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Credit the attached NEAR to the internal balance of `account_id` (defaults to the caller)
+    /// for wrapping. This is apart from the storage deposits of the account
+    #[payable]
+    pub fn deposit_near(&mut self, account_id: Option<ValidAccountId>) {
+        self.paused.assert_deposit_active();
+        if !self.set_info.ratios.iter().any(|ratio| is_native_near(&ratio.token_id)) {
+            panic!("Native NEAR is not a component of the set");
+        }
+        let amount = env::attached_deposit();
+        if amount == 0 {
+            panic!("Expected some NEAR to be attached");
+        }
+        let account_id: AccountId =
+            account_id.map(|a| a.into()).unwrap_or_else(env::predecessor_account_id);
+        self.increase_balance(&account_id, &NATIVE_NEAR_ID.to_string(), amount);
+        log!("Deposited {} yoctoNEAR for @{}", amount, account_id);
+    }
+
+    /// Withdraw `amount` native NEAR from the caller's internal balance
+    #[payable]
+    pub fn withdraw_near(&mut self, amount: U128) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.subtract_balance(&account_id, &NATIVE_NEAR_ID.to_string(), amount.0);
+        self.transfer_near(&account_id, &account_id, amount.0)
+    }
+}
+
+impl Contract {
+    /// Send `amount` native NEAR taken from the internal balance of `account_id` to
+    /// `receiver_id`, putting it back if the transfer fails
+    pub(crate) fn transfer_near(
+        &self,
+        account_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+    ) -> Promise {
+        Promise::new(receiver_id.clone()).transfer(amount).then(ext_self::resolve_unwrap_withdraw(
+            account_id.clone(),
+            NATIVE_NEAR_ID.to_string(),
+            amount.into(),
+            false,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+}
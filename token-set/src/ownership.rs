@@ -0,0 +1,79 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId};
+
+use crate::utils::{assert_valid_delay, timestamp_after};
+use crate::{Contract, ContractContract};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+/// An owner proposed by the current owner who has not accepted the ownership yet
+pub struct PendingOwner {
+    pub account_id: AccountId,
+    /// The block timestamp, in nanoseconds, after which the proposal can no longer be accepted
+    pub expires_at: Option<U64>,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Propose `new_owner` as the owner of the set, replacing any previous proposal.
+    /// If `expires_in` is given, the proposal can only be accepted within that many seconds
+    #[payable]
+    pub fn propose_owner(&mut self, new_owner: ValidAccountId, expires_in: Option<u64>) {
+        assert_one_yocto();
+        self.assert_owner();
+        let pending = PendingOwner {
+            account_id: new_owner.into(),
+            expires_at: expires_in.map(|seconds| {
+                assert_valid_delay("expiry", seconds);
+                timestamp_after(seconds).into()
+            }),
+        };
+        log!("Proposed @{} as the new owner", pending.account_id);
+        self.pending_owner = Some(pending);
+    }
+
+    /// Accept the ownership of the set as the proposed owner. The mint fees of the set go to
+    /// the new owner from now on
+    #[payable]
+    pub fn accept_ownership(&mut self) {
+        assert_one_yocto();
+        let pending =
+            self.pending_owner.take().unwrap_or_else(|| panic!("There is no pending owner"));
+        if env::predecessor_account_id() != pending.account_id {
+            panic!("Only the proposed owner can accept the ownership");
+        }
+        if let Some(expires_at) = pending.expires_at {
+            if env::block_timestamp() > expires_at.0 {
+                panic!("The ownership proposal expired at {}", expires_at.0);
+            }
+        }
+        // The streaming fees accrued so far belong to the previous owner
+        self.accrue_streaming_fees();
+
+        // Register the new owner with the token, as on initialization
+        if !self.token.accounts.contains_key(&pending.account_id) {
+            self.token.internal_register_account(&pending.account_id);
+        }
+        log!("Transferred the ownership from @{} to @{}", self.owner_id, pending.account_id);
+        self.owner_id = pending.account_id;
+    }
+
+    #[payable]
+    pub fn cancel_owner_proposal(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        if self.pending_owner.take().is_none() {
+            panic!("There is no pending owner");
+        }
+    }
+
+    pub fn get_owner_id(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    pub fn pending_owner(&self) -> Option<PendingOwner> {
+        self.pending_owner.clone()
+    }
+}
@@ -0,0 +1,136 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::ValidAccountId;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId};
+
+use crate::{Contract, ContractContract};
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+/// Which actions of the set are paused
+pub struct PauseStatus {
+    /// Wrapping internal balances into set tokens
+    pub wrap: bool,
+    /// Unwrapping set tokens into internal balances
+    pub unwrap: bool,
+    /// Depositing underlying tokens and native NEAR into internal balances
+    pub deposit: bool,
+    /// Transferring set tokens
+    pub transfer: bool,
+}
+
+impl PauseStatus {
+    pub(crate) fn assert_wrap_active(&self) {
+        if self.wrap {
+            panic!("Wrapping is paused");
+        }
+    }
+
+    pub(crate) fn assert_unwrap_active(&self) {
+        if self.unwrap {
+            panic!("Unwrapping is paused");
+        }
+    }
+
+    pub(crate) fn assert_deposit_active(&self) {
+        if self.deposit {
+            panic!("Deposits are paused");
+        }
+    }
+
+    pub(crate) fn assert_transfer_active(&self) {
+        if self.transfer {
+            panic!("Transfers are paused");
+        }
+    }
+
+    /// Set the given flags to `paused`, leaving the others unchanged
+    fn update(
+        &mut self,
+        wrap: Option<bool>,
+        unwrap: Option<bool>,
+        deposit: Option<bool>,
+        transfer: Option<bool>,
+        paused: bool,
+    ) {
+        for (flag, selected) in vec![
+            (&mut self.wrap, wrap),
+            (&mut self.unwrap, unwrap),
+            (&mut self.deposit, deposit),
+            (&mut self.transfer, transfer),
+        ] {
+            if selected.unwrap_or(false) {
+                *flag = paused;
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Pause the selected actions. Can be called by the owner or the guardian, so that
+    /// issuance can be stopped right away while holders can still exit
+    #[payable]
+    pub fn pause(
+        &mut self,
+        wrap: Option<bool>,
+        unwrap: Option<bool>,
+        deposit: Option<bool>,
+        transfer: Option<bool>,
+    ) {
+        assert_one_yocto();
+        self.assert_owner_or_guardian();
+        self.paused.update(wrap, unwrap, deposit, transfer, true);
+        log!(
+            "@{} paused the set, the pause status is now {:?}",
+            env::predecessor_account_id(),
+            self.paused
+        );
+    }
+
+    /// Unpause the selected actions. Can only be called by the owner, and not before a migrated
+    /// set has seeded its internal balance totals
+    #[payable]
+    pub fn unpause(
+        &mut self,
+        wrap: Option<bool>,
+        unwrap: Option<bool>,
+        deposit: Option<bool>,
+        transfer: Option<bool>,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        if self.internal_totals_seeding.is_some() {
+            panic!("Expected the internal balance totals to be seeded first");
+        }
+        self.paused.update(wrap, unwrap, deposit, transfer, false);
+        log!("@{} unpaused the set, the pause status is now {:?}", self.owner_id, self.paused);
+    }
+
+    /// Set the guardian who can pause the set alongside the owner, or remove it
+    #[payable]
+    pub fn set_guardian(&mut self, guardian_id: Option<ValidAccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.guardian_id = guardian_id.map(|g| g.into());
+    }
+
+    pub fn get_guardian_id(&self) -> Option<AccountId> {
+        self.guardian_id.clone()
+    }
+
+    pub fn pause_status(&self) -> PauseStatus {
+        self.paused.clone()
+    }
+}
+
+impl Contract {
+    pub(crate) fn assert_owner_or_guardian(&self) {
+        let caller = env::predecessor_account_id();
+        if caller != self.owner_id && Some(&caller) != self.guardian_id.as_ref() {
+            panic!("Only the owner or the guardian can call this method");
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId};
+
+use crate::events::emit_fee_updated;
+use crate::utils::timestamp_after;
+use crate::{Contract, ContractContract};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+/// A change of the platform fee which can be applied once the fee change delay has passed
+pub struct PendingPlatformFeeChange {
+    pub platform_fee: U128,
+    /// The block timestamp, in nanoseconds, from which the change can be applied
+    pub applicable_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Propose changing the platform fee. The fee can be lowered or raised up to the maximum
+    /// platform fee set on initialization
+    #[payable]
+    pub fn propose_platform_fee(&mut self, new_fee: U128) {
+        assert_one_yocto();
+        self.assert_platform();
+        let max_platform_fee = self.set_info.fee.max_platform_fee;
+        if new_fee.0 > max_platform_fee {
+            panic!("Expected the platform fee to be at most {}", max_platform_fee);
+        }
+        let mut fee = self.set_info.fee.clone();
+        fee.platform_fee = new_fee.0;
+        fee.assert_valid();
+
+        let change = PendingPlatformFeeChange {
+            platform_fee: new_fee,
+            applicable_at: timestamp_after(self.set_info.fee_change_delay).into(),
+        };
+        log!("Proposed a platform fee change applicable from {}", change.applicable_at.0);
+        self.set_info.pending_platform_fee_change = Some(change);
+    }
+
+    /// Apply the pending platform fee change once its delay has passed. Can be called by anyone
+    pub fn apply_platform_fee_change(&mut self) {
+        let change = self
+            .set_info
+            .pending_platform_fee_change
+            .take()
+            .unwrap_or_else(|| panic!("There is no pending platform fee change"));
+        if env::block_timestamp() < change.applicable_at.0 {
+            panic!("The platform fee change can only be applied from {}", change.applicable_at.0);
+        }
+        self.set_info.fee.platform_fee = change.platform_fee.0;
+        self.set_info.fee.assert_valid();
+        emit_fee_updated(&self.set_info.fee);
+        log!("Applied the platform fee change to {}", change.platform_fee.0);
+    }
+
+    #[payable]
+    pub fn cancel_platform_fee_change(&mut self) {
+        assert_one_yocto();
+        self.assert_platform();
+        if self.set_info.pending_platform_fee_change.take().is_none() {
+            panic!("There is no pending platform fee change");
+        }
+        log!("Cancelled the pending platform fee change");
+    }
+
+    /// Propose `new_platform_id` as the platform account, which has to accept with
+    /// `accept_platform_id`. The new account has to be registered with the set token
+    #[payable]
+    pub fn propose_platform_id(&mut self, new_platform_id: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_platform();
+        self.assert_token_account_registered(new_platform_id.as_ref());
+        log!("Proposed @{} as the new platform", new_platform_id.as_ref());
+        self.set_info.pending_platform_id = Some(new_platform_id.into());
+    }
+
+    /// Accept being the platform account of the set as the proposed platform account
+    #[payable]
+    pub fn accept_platform_id(&mut self) {
+        assert_one_yocto();
+        let new_platform_id = self
+            .set_info
+            .pending_platform_id
+            .take()
+            .unwrap_or_else(|| panic!("There is no pending platform account"));
+        if env::predecessor_account_id() != new_platform_id {
+            panic!("Only the proposed platform account can accept");
+        }
+        // The streaming fees accrued so far belong to the previous platform account
+        self.accrue_streaming_fees();
+        log!(
+            "Transferred the platform account from @{} to @{}",
+            self.set_info.fee.platform_id,
+            new_platform_id
+        );
+        self.set_info.fee.platform_id = new_platform_id;
+        emit_fee_updated(&self.set_info.fee);
+    }
+
+    #[payable]
+    pub fn cancel_platform_id_proposal(&mut self) {
+        assert_one_yocto();
+        self.assert_platform();
+        if self.set_info.pending_platform_id.take().is_none() {
+            panic!("There is no pending platform account");
+        }
+    }
+}
+
+impl Contract {
+    pub(crate) fn assert_platform(&self) {
+        if env::predecessor_account_id() != self.set_info.fee.platform_id {
+            panic!("Only the platform can call this method");
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, AccountId, Balance};
+
+use crate::{Contract, ContractContract, TokenAmount};
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WrapPreview {
+    /// The total amount of set tokens minted
+    pub amount: U128,
+    /// The amount of set tokens minted to the wrapper
+    pub wrapper_amount: U128,
+    /// The amount of set tokens minted to the owner as a fee
+    pub owner_fee_amount: U128,
+    /// The amount of set tokens minted to the platform as a fee
+    pub platform_fee_amount: U128,
+    /// The maximum amount of set tokens the account can wrap with its internal balances
+    pub max_amount: U128,
+    /// Whether the amount is divisible by the wrap to underlying ratio, which wrapping it
+    /// requires
+    pub divisible: bool,
+    /// The underlying tokens taken from the internal balances
+    pub underlying: Vec<TokenAmount>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UnwrapPreview {
+    /// The amount of set tokens redeemed
+    pub amount: U128,
+    /// The amount of set tokens burned
+    pub burned_amount: U128,
+    /// The amount of set tokens which cannot be burned and stays with the redeemer
+    pub remainder_amount: U128,
+    /// The amount of set tokens paid to the owner as a fee
+    pub owner_fee_amount: U128,
+    /// The amount of set tokens paid to the platform as a fee
+    pub platform_fee_amount: U128,
+    /// Whether the amount net of the redemption fee is divisible by the wrap to underlying
+    /// ratio, which unwrapping it without `round_down` requires
+    pub divisible: bool,
+    /// The underlying tokens released to the internal balances
+    pub underlying: Vec<TokenAmount>,
+    /// The underlying tokens paid to the owner as a fee
+    pub owner_fee_underlying: Vec<TokenAmount>,
+    /// The underlying tokens paid to the platform as a fee
+    pub platform_fee_underlying: Vec<TokenAmount>,
+}
+
+pub(crate) fn to_token_amounts(amounts: Vec<(AccountId, Balance)>) -> Vec<TokenAmount> {
+    amounts
+        .into_iter()
+        .map(|(token_id, amount)| TokenAmount { token_id, amount: amount.into() })
+        .collect()
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Preview wrapping `amount` (defaults to the maximum) for `account_id` like `wrap` with
+    /// `round_down` would, with the pending streaming fees accrued.
+    /// If the amount is not divisible by the wrap to underlying ratio, wrapping it fails and
+    /// the underlying amounts are for the amount rounded down
+    pub fn preview_wrap(
+        &self,
+        account_id: ValidAccountId,
+        amount: Option<U128>,
+        round_down: Option<bool>,
+    ) -> WrapPreview {
+        let pricing = self.accrued_pricing();
+        let (amount, max_amount) = self.wrap_amount(
+            &pricing,
+            account_id.as_ref(),
+            amount.map(|a| a.0),
+            round_down.unwrap_or(false),
+        );
+        let wrap_to_underlying_ratio = self.set_info.wrap_to_underlying_ratio;
+        let (wrapper_amount, owner_fee_amount, platform_fee_amount) =
+            self.set_info.fee.mint_fee_split(amount);
+
+        WrapPreview {
+            amount: amount.into(),
+            wrapper_amount: wrapper_amount.into(),
+            owner_fee_amount: owner_fee_amount.into(),
+            platform_fee_amount: platform_fee_amount.into(),
+            max_amount: max_amount.into(),
+            divisible: amount % wrap_to_underlying_ratio == 0,
+            underlying: to_token_amounts(
+                pricing.consumed_amounts(amount / wrap_to_underlying_ratio),
+            ),
+        }
+    }
+
+    /// Preview unwrapping `amount` set tokens like `unwrap` with `round_down` would, including
+    /// the redemption fee and with the pending streaming fees accrued
+    pub fn preview_unwrap(&self, amount: U128, round_down: Option<bool>) -> UnwrapPreview {
+        let amount: Balance = amount.into();
+        let amount =
+            if round_down.unwrap_or(false) { self.round_to_wrap_ratio(amount) } else { amount };
+        let redemption = self.redemption(&self.accrued_pricing(), amount);
+
+        UnwrapPreview {
+            amount: amount.into(),
+            burned_amount: redemption.burned.into(),
+            remainder_amount: redemption.remainder.into(),
+            owner_fee_amount: redemption.owner_fee.into(),
+            platform_fee_amount: redemption.platform_fee.into(),
+            divisible: redemption.remainder == 0,
+            underlying: to_token_amounts(redemption.released),
+            owner_fee_underlying: to_token_amounts(redemption.owner_fee_underlying),
+            platform_fee_underlying: to_token_amounts(redemption.platform_fee_underlying),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U64;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId};
+use shared::{TokenWithRatio, TokenWithRatioValid};
+
+use crate::token_set_info::{ratio_amount, validate_ratios};
+use crate::utils::timestamp_after;
+use crate::{Contract, ContractContract};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+/// A new composition of the set which the owner can switch to once the notice period is over
+pub struct PendingRecomposition {
+    pub ratios: Vec<TokenWithRatio>,
+    /// The block timestamp, in nanoseconds, from which the recomposition can be executed
+    pub executable_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Propose replacing the ratios of the set with `new_ratios`, in base units.
+    /// Until the recomposition is executed, holders can still unwrap under the current ratios
+    #[payable]
+    pub fn propose_recomposition(&mut self, new_ratios: Vec<TokenWithRatioValid>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.set_info.assert_ready();
+        let recomposition = PendingRecomposition {
+            ratios: validate_ratios(new_ratios),
+            executable_at: timestamp_after(self.set_info.recomposition_notice).into(),
+        };
+        log!("Proposed a recomposition executable from {}", recomposition.executable_at.0);
+        self.set_info.pending_recomposition = Some(recomposition);
+    }
+
+    /// Switch the set to the proposed ratios. Every unit of the set is exchanged one to one:
+    /// the locked backing is released to the owner's internal balance and the backing under
+    /// the new ratios is taken from it.
+    ///
+    /// The owner has to pre-fund the recomposition: before executing, its internal balance must
+    /// hold the new ratios times the supply, rounded up, apart from what the released backing
+    /// covers. Otherwise the call panics and the set keeps its current ratios
+    #[payable]
+    pub fn execute_recomposition(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.set_info.assert_no_auction();
+        self.set_info.assert_no_flash_mint();
+        let recomposition = self
+            .set_info
+            .pending_recomposition
+            .take()
+            .unwrap_or_else(|| panic!("There is no pending recomposition"));
+        if env::block_timestamp() < recomposition.executable_at.0 {
+            panic!("The recomposition can only be executed from {}", recomposition.executable_at.0);
+        }
+        let owner_id = self.owner_id.clone();
+        self.assert_internal_account_registered(&owner_id);
+        self.accrue_streaming_fees();
+
+        // Release all of the old backing and lock the new backing rounded up, so that the set
+        // stays fully backed
+        let old_token_ids: Vec<AccountId> =
+            self.set_info.ratios.iter().map(|ratio| ratio.token_id).collect();
+        for token_id in old_token_ids {
+            let amount = self.locked_backing_of(&token_id);
+            self.release_backing(&token_id, amount);
+            self.increase_balance(&owner_id, &token_id, amount);
+        }
+        let units = self.supply_units();
+        for ratio in recomposition.ratios.iter() {
+            let amount = ratio_amount(ratio, units, true);
+            let balance = self.get_ft_balance_internal(&owner_id, &ratio.token_id);
+            if balance < amount {
+                panic!(
+                    "Expected the owner to hold {} of {} to back the recomposition, got {}",
+                    amount, ratio.token_id, balance
+                );
+            }
+            self.subtract_balance(&owner_id, &ratio.token_id, amount);
+            self.lock_backing(&ratio.token_id, amount);
+        }
+
+        let old_len = self.set_info.ratios.len();
+        let new_len = recomposition.ratios.len();
+        self.replace_ratios(recomposition.ratios);
+        log!("Recomposed the set from {} to {} tokens", old_len, new_len);
+    }
+
+    #[payable]
+    pub fn cancel_recomposition(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        if self.set_info.pending_recomposition.take().is_none() {
+            panic!("There is no pending recomposition");
+        }
+        log!("Cancelled the pending recomposition");
+    }
+}
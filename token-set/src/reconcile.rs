@@ -0,0 +1,122 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, serde_json, AccountId, Promise, PromiseResult};
+
+use crate::external::{ext_ft, ext_self, GAS_FOR_FT_BALANCE_OF, GAS_FOR_RESOLVE_RECONCILE};
+use crate::native::is_native_near;
+use crate::{Contract, ContractContract};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+/// How the balance the set holds of a token compares to its own bookkeeping
+pub struct TokenReconciliation {
+    pub token_id: AccountId,
+    /// The balance of the set on the token contract
+    pub held: U128,
+    /// The locked backing plus the internal balances of all accounts, the escrows and the
+    /// flash loan pool
+    pub expected: U128,
+    pub surplus: U128,
+    pub deficit: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Compare the balance of the set on every underlying token contract to the locked backing
+    /// plus the internal balances, the escrows of closed accounts, the flash loan pools and the
+    /// backing of the hosted sets.
+    /// If `auto_pause` is set, wrapping and deposits are paused on a deficit, which only the
+    /// owner or the guardian can ask for.
+    ///
+    /// Transfers in flight can show up as a surplus or deficit, so this is meant for monitoring
+    pub fn reconcile(&mut self, auto_pause: Option<bool>) -> Promise {
+        let auto_pause = auto_pause.unwrap_or(false);
+        if auto_pause {
+            self.assert_owner_or_guardian();
+        }
+        // Native NEAR is mixed with the storage deposits in the account balance, so it is left out
+        let mut token_ids: Vec<AccountId> = self
+            .set_info
+            .ratios
+            .iter()
+            .map(|ratio| ratio.token_id)
+            .filter(|token_id| !is_native_near(token_id))
+            .collect();
+        if let Some(auction) = self.set_info.auction.as_ref() {
+            if !token_ids.contains(&auction.buy_token_id) {
+                token_ids.push(auction.buy_token_id.clone());
+            }
+        }
+
+        let mut promise: Option<Promise> = None;
+        for token_id in token_ids.iter() {
+            let balance_of = ext_ft::ft_balance_of(
+                env::current_account_id(),
+                token_id,
+                0,
+                GAS_FOR_FT_BALANCE_OF,
+            );
+            promise = Some(match promise {
+                Some(p) => p.and(balance_of),
+                None => balance_of,
+            });
+        }
+        promise.unwrap().then(ext_self::resolve_reconcile(
+            token_ids,
+            auto_pause,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_RECONCILE,
+        ))
+    }
+
+    #[private]
+    pub fn resolve_reconcile(
+        &mut self,
+        token_ids: Vec<AccountId>,
+        auto_pause: bool,
+    ) -> Vec<TokenReconciliation> {
+        let report: Vec<TokenReconciliation> = token_ids
+            .into_iter()
+            .enumerate()
+            .map(|(i, token_id)| {
+                let held = match env::promise_result(i as u64) {
+                    PromiseResult::Successful(value) => {
+                        serde_json::from_slice::<U128>(&value)
+                            .unwrap_or_else(|_| panic!("Invalid balance from {}", token_id))
+                            .0
+                    }
+                    _ => panic!("Failed to get the balance of the set from {}", token_id),
+                };
+                let expected = self.locked_backing_of(&token_id)
+                    + self.internal_totals.get(&token_id).unwrap_or(0)
+                    + self.escrow_total(&token_id)
+                    + self.set_info.flash_loan_pool_amount(&token_id)
+                    + self.hosted_backing_of(&token_id);
+                let reconciliation = TokenReconciliation {
+                    held: held.into(),
+                    expected: expected.into(),
+                    surplus: held.saturating_sub(expected).into(),
+                    deficit: expected.saturating_sub(held).into(),
+                    token_id,
+                };
+                log!(
+                    "{}: held {}, expected {}, surplus {}, deficit {}",
+                    reconciliation.token_id,
+                    held,
+                    expected,
+                    reconciliation.surplus.0,
+                    reconciliation.deficit.0
+                );
+                reconciliation
+            })
+            .collect();
+
+        if auto_pause && report.iter().any(|r| r.deficit.0 > 0) {
+            self.paused.wrap = true;
+            self.paused.deposit = true;
+            log!("Paused wrapping and deposits because of a deficit");
+        }
+        report
+    }
+}
@@ -1,135 +1,527 @@
 use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
 use std::collections::HashSet;
 
-use near_sdk::{collections::Vector, env, AccountId, Balance};
+use near_sdk::collections::{LookupMap, UnorderedMap, Vector};
+use near_sdk::{env, json_types::U128, AccountId, Balance};
+use shared::SetConfig;
 
-use crate::{utils::U256, Contract, FeeReceiver, SetInfo, TokenWithRatio, TokenWithRatioValid};
+use crate::events::{emit_fee_minted, emit_ft_mint, emit_set_wrap, FeeKind};
+use crate::preview::to_token_amounts;
+use crate::utils::{assert_valid_notice, fit_fraction, mul_div, reduce_fraction, U256};
+use crate::{Contract, FeeReceiver, SetInfo, SetStatus, TokenWithRatio, TokenWithRatioValid};
 
+/// The default wrap to underlying ratio, used if none is given on initialization
 pub const WRAP_TO_UNDERLYING_RATIO: u128 = 1_000_000;
-const FEE_DENOMINATOR: u128 = 1_000_000_000_000_000;
+/// The default number of decimals of the set token
+pub const DEFAULT_DECIMALS: u8 = 24;
+/// The default number of seconds between proposing a fee change and applying it
+pub const DEFAULT_FEE_CHANGE_DELAY: u64 = 24 * 60 * 60;
+/// The default maximum amount by which a fee can increase in a single change, 1%
+pub const DEFAULT_MAX_FEE_INCREASE: u128 = 10_000_000_000_000;
+/// The default number of seconds between proposing a recomposition and executing it
+pub const DEFAULT_RECOMPOSITION_NOTICE: u64 = 7 * 24 * 60 * 60;
+/// The default number of seconds a closed account can claim its escrowed tokens for
+pub const DEFAULT_ESCROW_WINDOW: u64 = 30 * 24 * 60 * 60;
+/// The maximum number of seconds of any delay, notice period or window
+pub const MAX_DELAY: u64 = 365 * 24 * 60 * 60;
+/// The minimum number of seconds of a fee change delay or a recomposition notice, so holders
+/// can react to the change
+pub const MIN_DELAY: u64 = 60 * 60;
+pub(crate) const FEE_DENOMINATOR: u128 = 1_000_000_000_000_000;
+pub(crate) const NANOS_PER_SECOND: u64 = 1_000_000_000;
 
 impl SetInfo {
-    pub(crate) fn new(set_ratios: Vec<TokenWithRatioValid>, set_initial_fee: FeeReceiver) -> Self {
-        if set_ratios.len() == 0 {
-            panic!("Expected at least one token in the set");
+    /// The collections of the set are stored under `prefix`, which is empty for the set of the
+    /// contract itself
+    pub(crate) fn new(
+        prefix: &[u8],
+        set_ratios: Vec<TokenWithRatioValid>,
+        set_initial_fee: FeeReceiver,
+        decimals: u8,
+        config: &SetConfig,
+    ) -> Self {
+        let wrap_to_underlying_ratio =
+            config.wrap_to_underlying_ratio.map(|r| r.0).unwrap_or(WRAP_TO_UNDERLYING_RATIO);
+        if wrap_to_underlying_ratio == 0 {
+            panic!("Expected the wrap to underlying ratio to be greater than 0");
+        }
+        let one_set_token = 10_u128
+            .checked_pow(decimals as u32)
+            .unwrap_or_else(|| panic!("Expected the decimals to be at most 38, got {}", decimals));
+        if wrap_to_underlying_ratio > one_set_token {
+            panic!(
+                "Expected the wrap to underlying ratio to be at most one whole set token of {}",
+                one_set_token
+            );
+        }
+
+        let key = |name: &[u8]| [prefix, name].concat();
+        let mut ratios = Vector::new(key(b"set-ratio"));
+        ratios.extend(validate_ratios(set_ratios));
+        set_initial_fee.assert_valid();
+        let status = if config.ratios_in_whole_units.unwrap_or(false) {
+            SetStatus::PendingMetadata
+        } else {
+            SetStatus::Ready
+        };
+        let fee_change_delay = config.fee_change_delay.unwrap_or(DEFAULT_FEE_CHANGE_DELAY);
+        assert_valid_notice("fee change delay", fee_change_delay);
+        let recomposition_notice =
+            config.recomposition_notice.unwrap_or(DEFAULT_RECOMPOSITION_NOTICE);
+        assert_valid_notice("recomposition notice", recomposition_notice);
+        Self {
+            ratios,
+            fee: set_initial_fee,
+            decimals,
+            wrap_to_underlying_ratio,
+            status,
+            last_fee_accrual: env::block_timestamp(),
+            fee_change_delay,
+            max_fee_increase: config
+                .max_fee_increase
+                .map(|f| f.0)
+                .unwrap_or(DEFAULT_MAX_FEE_INCREASE),
+            pending_fee_change: None,
+            pending_platform_fee_change: None,
+            pending_platform_id: None,
+            recomposition_notice,
+            pending_recomposition: None,
+            auction: None,
+            locked_backing: UnorderedMap::new(key(b"locked-backing")),
+            flash_loan_fee: None,
+            flash_loan_borrowers: vec![],
+            flash_loan: None,
+            flash_loan_pools: UnorderedMap::new(key(b"flash-loan-pools")),
+            flash_loan_shares: LookupMap::new(key(b"flash-loan-shares")),
+            flash_mint_fee: None,
+            flash_mint: None,
+        }
+    }
+
+    /// Convert ratios given in whole units into base units using the decimals of each token
+    pub(crate) fn normalize_ratios(&mut self, token_decimals: Vec<u8>) -> Result<(), String> {
+        if token_decimals.len() as u64 != self.ratios.len() {
+            return Err(format!(
+                "Expected the decimals of {} tokens, got {}",
+                self.ratios.len(),
+                token_decimals.len()
+            ));
+        }
+        let set_unit = U256::from(10u64).pow(U256::from(self.decimals));
+        // Normalize every ratio before replacing any of them, so that an error leaves the
+        // ratios untouched
+        let mut normalized = Vec::with_capacity(token_decimals.len());
+        for (mut ratio, token_decimal) in self.ratios.iter().zip(token_decimals.into_iter()) {
+            if token_decimal > 38 {
+                return Err(format!("The decimals of {} are too large", ratio.token_id));
+            }
+            // ratio * 10^token_decimals base units per whole set token, where one whole
+            // set token is 10^decimals / wrap_to_underlying_ratio units of the set
+            let (numerator, denominator) = reduce_fraction(
+                U256::from(ratio.ratio.0)
+                    * U256::from(10u64).pow(U256::from(token_decimal))
+                    * U256::from(self.wrap_to_underlying_ratio),
+                U256::from(ratio.denominator.0) * set_unit,
+            );
+            if numerator > U256::from(u128::MAX) || denominator > U256::from(u128::MAX) {
+                return Err(format!("The normalized ratio of {} overflows", ratio.token_id));
+            }
+            ratio.ratio = U128(numerator.as_u128());
+            ratio.denominator = U128(denominator.as_u128());
+            ratio.decimals = Some(token_decimal);
+            normalized.push(ratio);
+        }
+        for (i, ratio) in normalized.iter().enumerate() {
+            self.ratios.replace(i as u64, ratio);
         }
+        Ok(())
+    }
 
-        let mut token_ids: HashSet<AccountId> = HashSet::default();
+    /// Scale every ratio by `numerator / denominator`, rounding down so that the set stays
+    /// fully backed
+    pub(crate) fn scale_ratios(&mut self, numerator: Balance, denominator: Balance) {
+        for i in 0..self.ratios.len() {
+            let ratio = scale_ratio(&self.ratios.get(i).unwrap(), numerator, denominator);
+            self.ratios.replace(i, &ratio);
+        }
+    }
+
+    pub(crate) fn assert_ready(&self) {
+        match self.status {
+            SetStatus::Ready => {}
+            SetStatus::PendingMetadata => {
+                panic!("The set is waiting for the metadata of its underlying tokens")
+            }
+            SetStatus::Failed => panic!("The set failed to initialize"),
+        }
+    }
+}
 
-        let mut ratios = Vector::new(b"set-ratio".to_vec());
+/// Check that the ratios are non-empty with unique tokens and non-zero fractions,
+/// and reduce every fraction
+pub(crate) fn validate_ratios(set_ratios: Vec<TokenWithRatioValid>) -> Vec<TokenWithRatio> {
+    if set_ratios.len() == 0 {
+        panic!("Expected at least one token in the set");
+    }
+    let mut token_ids: HashSet<AccountId> = HashSet::default();
 
-        for ratio in set_ratios {
+    set_ratios
+        .into_iter()
+        .map(|ratio| {
             let not_present = token_ids.insert(ratio.token_id.clone().to_string());
             if !not_present {
                 panic!("Each token in the ratio must be unique");
             }
-            ratios.push(&TokenWithRatio { token_id: ratio.token_id.into(), ratio: ratio.ratio });
-        }
-        if set_initial_fee.owner_fee > FEE_DENOMINATOR
-            || set_initial_fee.platform_fee > FEE_DENOMINATOR
-        {
-            panic!("Expected the fees to be less than the fee denominator of {}", FEE_DENOMINATOR);
-        }
-        if set_initial_fee.owner_fee + set_initial_fee.platform_fee > FEE_DENOMINATOR {
-            panic!(
-                "Expected the sum of fees to be less than the fee denominator of {}",
-                FEE_DENOMINATOR
-            );
-        }
-        Self { ratios, fee: set_initial_fee }
+            let denominator = ratio.denominator.map(|d| d.0).unwrap_or(1);
+            if ratio.ratio.0 == 0 || denominator == 0 {
+                panic!(
+                    "Expected the ratio of {} to be a non-zero fraction",
+                    ratio.token_id.as_ref()
+                );
+            }
+            let (numerator, denominator) =
+                reduce_fraction(U256::from(ratio.ratio.0), U256::from(denominator));
+            TokenWithRatio {
+                token_id: ratio.token_id.into(),
+                ratio: U128(numerator.as_u128()),
+                denominator: U128(denominator.as_u128()),
+                decimals: None,
+            }
+        })
+        .collect()
+}
+
+/// Scale `ratio` by `numerator / denominator`, rounding down
+pub(crate) fn scale_ratio(
+    ratio: &TokenWithRatio,
+    numerator: Balance,
+    denominator: Balance,
+) -> TokenWithRatio {
+    let (scaled_numerator, scaled_denominator) = reduce_fraction(
+        U256::from(ratio.ratio.0) * U256::from(numerator),
+        U256::from(ratio.denominator.0) * U256::from(denominator),
+    );
+    let (scaled_numerator, scaled_denominator) = fit_fraction(scaled_numerator, scaled_denominator);
+    if scaled_numerator == 0 {
+        panic!("The ratio of {} is too small to be scaled", ratio.token_id);
+    }
+    TokenWithRatio {
+        ratio: U128(scaled_numerator),
+        denominator: U128(scaled_denominator),
+        ..ratio.clone()
+    }
+}
+
+/// The number of units of the set backing `total_supply`, rounded up
+fn units_of_supply(total_supply: Balance, wrap_to_underlying_ratio: Balance) -> Balance {
+    (total_supply + wrap_to_underlying_ratio - 1) / wrap_to_underlying_ratio
+}
+
+/// The ratios and the total supply the amounts of wrapping and unwrapping are computed from.
+/// Calls accrue the pending streaming fees before pricing, and previews price with the
+/// pending fees accrued, so that both compute the same amounts
+pub(crate) struct Pricing {
+    pub(crate) ratios: Vec<TokenWithRatio>,
+    pub(crate) total_supply: Balance,
+    pub(crate) wrap_to_underlying_ratio: Balance,
+}
+
+impl Pricing {
+    /// The amount of each underlying token released for `units` units of the set
+    pub(crate) fn released_amounts(&self, units: Balance) -> Vec<(AccountId, Balance)> {
+        self.ratios
+            .iter()
+            .map(|ratio| (ratio.token_id.clone(), ratio_amount(ratio, units, false)))
+            .collect()
+    }
+
+    /// The amount of each underlying token taken in for `units` units of the set
+    pub(crate) fn consumed_amounts(&self, units: Balance) -> Vec<(AccountId, Balance)> {
+        self.ratios
+            .iter()
+            .map(|ratio| (ratio.token_id.clone(), ratio_amount(ratio, units, true)))
+            .collect()
+    }
+
+    /// The number of units of the set backing the total supply, rounded up
+    pub(crate) fn supply_units(&self) -> Balance {
+        units_of_supply(self.total_supply, self.wrap_to_underlying_ratio)
     }
 }
 
+/// The amount of the ratio's token for `units` units of the set.
+/// Amounts taken in by the set are rounded up and amounts released are rounded down,
+/// so that the set can never be under-collateralized
+pub(crate) fn ratio_amount(ratio: &TokenWithRatio, units: Balance, round_up: bool) -> Balance {
+    mul_div(units, ratio.ratio.0, ratio.denominator.0, round_up)
+        .unwrap_or_else(|| panic!("The amount of {} overflows", ratio.token_id))
+}
+
 impl Contract {
-    pub(crate) fn on_burn(&mut self, account_id: AccountId, amount: Balance) {
-        if amount % WRAP_TO_UNDERLYING_RATIO != 0 {
+    /// The ratios and the total supply as they are stored, without the pending streaming fees
+    pub(crate) fn pricing(&self) -> Pricing {
+        Pricing {
+            ratios: self.set_info.ratios.to_vec(),
+            total_supply: self.token.total_supply,
+            wrap_to_underlying_ratio: self.set_info.wrap_to_underlying_ratio,
+        }
+    }
+
+    /// The amount of each underlying token released by burning `amount` set tokens
+    pub(crate) fn underlying_amounts(
+        &self,
+        pricing: &Pricing,
+        amount: Balance,
+    ) -> Vec<(AccountId, Balance)> {
+        let wrap_to_underlying_ratio = self.set_info.wrap_to_underlying_ratio;
+        if amount % wrap_to_underlying_ratio != 0 {
             panic!(
                 "Expected {} to be divisible by the wrap to underlying ratio of {}",
-                amount, WRAP_TO_UNDERLYING_RATIO
+                amount, wrap_to_underlying_ratio
             )
         }
-        let amount_underlying = amount / WRAP_TO_UNDERLYING_RATIO;
-        for i in 0..self.set_info.ratios.len() {
-            let ratio = &self.set_info.ratios.get(i).unwrap();
-            self.increase_balance(
-                &account_id,
-                &ratio.token_id,
-                ratio.ratio as u128 * amount_underlying,
-            );
+        let units = amount / wrap_to_underlying_ratio;
+        match self.set_info.auction.as_ref() {
+            Some(auction) => self.auction_released_amounts(auction, units, pricing.supply_units()),
+            None => pricing.released_amounts(units),
+        }
+    }
+
+    /// Credit the underlying tokens of `amount` set tokens burned by the fungible token to
+    /// `account_id`. Only whole units of the set are released, the backing of the rest stays
+    /// locked
+    pub(crate) fn on_burn(&mut self, account_id: AccountId, amount: Balance) {
+        let units = amount / self.set_info.wrap_to_underlying_ratio;
+        for (token_id, amount_underlying) in self.release_burned_units(units) {
+            self.increase_balance(&account_id, &token_id, amount_underlying);
+        }
+    }
+
+    /// Release the backing of `units` units of the set which were already taken out of the
+    /// supply, clamped to the locked backing, so that rounding can never fail a burn
+    ///
+    /// return the amounts released
+    pub(crate) fn release_burned_units(&mut self, units: Balance) -> Vec<(AccountId, Balance)> {
+        let total_units = self.supply_units() + units;
+        let amounts = match self.set_info.auction.clone() {
+            Some(auction) => self.auction_released_amounts(&auction, units, total_units),
+            None => self.pricing().released_amounts(units),
+        };
+        self.shrink_auction(units, total_units);
+        amounts
+            .into_iter()
+            .map(|(token_id, amount)| {
+                let amount = std::cmp::min(amount, self.locked_backing_of(&token_id));
+                self.release_backing(&token_id, amount);
+                (token_id, amount)
+            })
+            .collect()
+    }
+
+    /// The amount of `token_id` locked as backing of the set
+    pub(crate) fn locked_backing_of(&self, token_id: &AccountId) -> Balance {
+        self.set_info.locked_backing.get(token_id).unwrap_or(0)
+    }
+
+    pub(crate) fn lock_backing(&mut self, token_id: &AccountId, amount: Balance) {
+        let locked = self.locked_backing_of(token_id) + amount;
+        self.set_info.locked_backing.insert(token_id, &locked);
+    }
+
+    pub(crate) fn release_backing(&mut self, token_id: &AccountId, amount: Balance) {
+        let locked = self
+            .locked_backing_of(token_id)
+            .checked_sub(amount)
+            .unwrap_or_else(|| panic!("Not enough locked backing of {}", token_id));
+        if locked == 0 {
+            self.set_info.locked_backing.remove(token_id);
+        } else {
+            self.set_info.locked_backing.insert(token_id, &locked);
+        }
+    }
+
+    /// Redeem `amount` set tokens from `account_id` and credit the underlying tokens to the
+    /// internal balance of `receiver_id`. If `round_down` is set, only the part of the amount
+    /// divisible by the wrap to underlying ratio is redeemed, and the part of the amount net of
+    /// the redemption fee which cannot be burned stays with `account_id`. Otherwise the net
+    /// amount has to be divisible
+    ///
+    /// return the amount redeemed, including the redemption fee
+    pub(crate) fn unwrap_token(
+        &mut self,
+        account_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+        round_down: bool,
+    ) -> Balance {
+        let amount = if round_down { self.round_to_wrap_ratio(amount) } else { amount };
+        self.assert_internal_account_registered(receiver_id);
+        if !round_down {
+            let redemption = self.redemption(&self.accrued_pricing(), amount);
+            if redemption.remainder > 0 {
+                panic!(
+                    "Expected {} net of the redemption fee to be divisible by the wrap to underlying ratio of {}",
+                    redemption.burned + redemption.remainder,
+                    self.set_info.wrap_to_underlying_ratio
+                );
+            }
+        }
+        for (token_id, amount_underlying) in self.redeem(account_id, amount) {
+            self.increase_balance(receiver_id, &token_id, amount_underlying);
+        }
+        amount
+    }
+
+    /// Replace the ratios of the set and recalculate the minimum account storage for the new
+    /// number of tokens
+    pub(crate) fn replace_ratios(&mut self, ratios: Vec<TokenWithRatio>) {
+        // Without ratios the maximum wrappable amount is unbounded, and a zero denominator
+        // cannot be divided by
+        if ratios.is_empty() {
+            panic!("Expected at least one token in the set");
+        }
+        for ratio in ratios.iter() {
+            if ratio.ratio.0 == 0 || ratio.denominator.0 == 0 {
+                panic!("Expected the ratio of {} to be a non-zero fraction", ratio.token_id);
+            }
         }
+        let old_len = self.set_info.ratios.len() as Balance;
+        let new_len = ratios.len() as Balance;
+        self.set_info.ratios.clear();
+        self.set_info.ratios.extend(ratios);
+
+        let cost_for_one_balance = self.get_storage_cost_for_one_balance();
+        self.accounts.default_min_storage_bal = self.accounts.default_min_storage_bal
+            - old_len * cost_for_one_balance
+            + new_len * cost_for_one_balance;
+    }
+
+    /// The number of units of the set backing the total supply, rounded up
+    pub(crate) fn supply_units(&self) -> Balance {
+        units_of_supply(self.token.total_supply, self.set_info.wrap_to_underlying_ratio)
     }
 
-    pub(crate) fn unwrap_token(&mut self, amount: u128) {
-        let account_id = env::predecessor_account_id();
-        self.token.internal_withdraw(&account_id, amount);
-        self.on_burn(account_id, amount);
+    /// Round `amount` down to a multiple of the wrap to underlying ratio
+    pub(crate) fn round_to_wrap_ratio(&self, amount: Balance) -> Balance {
+        amount - amount % self.set_info.wrap_to_underlying_ratio
+    }
+
+    pub(crate) fn assert_internal_account_registered(&self, account_id: &AccountId) {
+        if self.accounts.get_account(account_id).is_none() {
+            panic!("Expected {} to be registered for internal balances", account_id);
+        }
     }
 
-    pub(crate) fn change_owner_fee(&mut self, new_fee: u128) {
-        if !self.set_info.fee.updatable {
-            panic!("Cannot update a token set fee unless the fee property is marked initially updatable")
+    pub(crate) fn assert_token_account_registered(&self, account_id: &AccountId) {
+        if !self.token.accounts.contains_key(account_id) {
+            panic!("Expected {} to be registered with the set token", account_id);
         }
-        self.set_info.fee.owner_fee = new_fee;
     }
 
     /// Decrease the balances of the underlying tokens and wrap the tokens.
     /// Also, send the apportioned fee amount
     ///
+    /// The underlying tokens are taken from `account_id` and the set tokens are minted to
+    /// `receiver_id`. If `round_down` is set, the amount is rounded down to a multiple of the
+    /// wrap to underlying ratio
+    ///
     /// return the amount wrapped and given to the wrapper
-    pub(crate) fn wrap_internal(&mut self, owner: &AccountId, amount: Option<Balance>) -> Balance {
-        let caller = env::predecessor_account_id();
-        let max_amount_wrapped = self.get_max_amount(&caller);
-        let amount_wrap = amount.unwrap_or(max_amount_wrapped);
+    pub(crate) fn wrap_internal(
+        &mut self,
+        owner: &AccountId,
+        account_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Option<Balance>,
+        round_down: bool,
+    ) -> Balance {
+        self.set_info.assert_ready();
+        self.set_info.assert_no_auction();
+        self.set_info.assert_no_flash_loan();
+        self.paused.assert_wrap_active();
+        self.assert_token_account_registered(receiver_id);
+        self.accrue_streaming_fees();
+        let (amount_wrap, max_amount_wrapped) =
+            self.wrap_amount(&self.pricing(), account_id, amount, round_down);
         if amount_wrap > max_amount_wrapped {
             panic!(
                 "Maximum amount that can be wrapped is {}, tried wrapping {}",
                 max_amount_wrapped, amount_wrap
             );
-        } else if amount_wrap % WRAP_TO_UNDERLYING_RATIO != 0 {
+        } else if amount_wrap % self.set_info.wrap_to_underlying_ratio != 0 {
             panic!(
                 "Expected {} to be divisible by the wrap to underlying ratio of {}",
-                amount_wrap, WRAP_TO_UNDERLYING_RATIO
+                amount_wrap, self.set_info.wrap_to_underlying_ratio
             );
         }
-        let amount_wrap_scaled_down = amount_wrap / WRAP_TO_UNDERLYING_RATIO;
-
-        let owner_inrcr = (U256::from(amount_wrap) * U256::from(self.set_info.fee.owner_fee)
-            / U256::from(FEE_DENOMINATOR))
-        .as_u128();
-        let platform_incr = (U256::from(amount_wrap) * U256::from(self.set_info.fee.platform_fee)
-            / U256::from(FEE_DENOMINATOR))
-        .as_u128();
+        let amount_wrap_scaled_down = amount_wrap / self.set_info.wrap_to_underlying_ratio;
 
-        let amount_wrap_caller = amount_wrap - owner_inrcr - platform_incr;
+        let (amount_wrap_caller, owner_inrcr, platform_incr) =
+            self.set_info.fee.mint_fee_split(amount_wrap);
 
         // Do the internal deposits
-        self.token.internal_deposit(&caller, amount_wrap_caller);
+        let platform_id = self.set_info.fee.platform_id.clone();
+        self.token.internal_deposit(receiver_id, amount_wrap_caller);
         self.token.internal_deposit(&owner, owner_inrcr);
-        self.token.internal_deposit(&self.set_info.fee.platform_id, platform_incr);
+        self.token.internal_deposit(&platform_id, platform_incr);
 
-        self.decrease_potentials(amount_wrap_scaled_down, &caller);
+        let consumed = self.decrease_potentials(amount_wrap_scaled_down, account_id);
 
+        emit_ft_mint(receiver_id, amount_wrap_caller, None);
+        emit_ft_mint(owner, owner_inrcr, Some("mint fee"));
+        emit_ft_mint(&platform_id, platform_incr, Some("mint fee"));
+        emit_fee_minted(FeeKind::Mint, owner, owner_inrcr, &platform_id, platform_incr);
+        emit_set_wrap(account_id, receiver_id, amount_wrap, to_token_amounts(consumed));
         amount_wrap
     }
 
-    fn decrease_potentials(&mut self, amount_out: Balance, account_id: &AccountId) {
-        for i in 0..self.set_info.ratios.len() {
-            let ratio = &self.set_info.ratios.get(i).unwrap();
-            self.subtract_balance(&account_id, &ratio.token_id, (ratio.ratio as u128) * amount_out)
+    /// The amount of set tokens wrapped for `amount`, defaulting to the maximum amount
+    /// `account_id` can wrap and rounded down if `round_down` is set, and the maximum amount
+    pub(crate) fn wrap_amount(
+        &self,
+        pricing: &Pricing,
+        account_id: &AccountId,
+        amount: Option<Balance>,
+        round_down: bool,
+    ) -> (Balance, Balance) {
+        let max_amount = self.get_max_amount(pricing, account_id);
+        let amount = amount.unwrap_or(max_amount);
+        let amount = if round_down { self.round_to_wrap_ratio(amount) } else { amount };
+        (amount, max_amount)
+    }
+
+    /// Move the underlying tokens for `amount_out` units from the internal balance of
+    /// `account_id` into the locked backing
+    ///
+    /// return the amounts moved
+    pub(crate) fn decrease_potentials(
+        &mut self,
+        amount_out: Balance,
+        account_id: &AccountId,
+    ) -> Vec<(AccountId, Balance)> {
+        let consumed = self.pricing().consumed_amounts(amount_out);
+        for (token_id, amount) in consumed.iter() {
+            self.subtract_balance(&account_id, token_id, *amount);
+            self.lock_backing(token_id, *amount);
         }
+        consumed
     }
 
-    fn get_max_amount(&self, account_id: &AccountId) -> Balance {
+    /// The largest amount of set tokens `account_id` can wrap with its internal balances
+    pub(crate) fn get_max_amount(&self, pricing: &Pricing, account_id: &AccountId) -> Balance {
         let mut min = u128::MAX;
-        for i in 0..self.set_info.ratios.len() {
-            let ratio = &self.set_info.ratios.get(i).unwrap();
+        for ratio in pricing.ratios.iter() {
             let bal = self.get_ft_balance_internal(account_id, &ratio.token_id);
 
-            let amount_out = bal / (ratio.ratio as u128);
+            // The largest amount out for which the rounded up ratio amount is at most the balance
+            let amount_out =
+                mul_div(bal, ratio.denominator.0, ratio.ratio.0, false).unwrap_or(u128::MAX);
             if amount_out < min {
                 min = amount_out;
             }
         }
-        min * WRAP_TO_UNDERLYING_RATIO
+        let wrap_to_underlying_ratio = pricing.wrap_to_underlying_ratio;
+        // Cap the amount at the largest wrappable amount which fits into a u128
+        min.checked_mul(wrap_to_underlying_ratio)
+            .unwrap_or(u128::MAX / wrap_to_underlying_ratio * wrap_to_underlying_ratio)
     }
 }
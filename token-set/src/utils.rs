@@ -1,6 +1,77 @@
+use near_sdk::env;
 use uint::construct_uint;
 
+use crate::token_set_info::{MAX_DELAY, MIN_DELAY, NANOS_PER_SECOND};
+
 construct_uint! {
     /// 256-bit unsigned integer.
     pub struct U256(4);
 }
+
+/// Calculate `a * b / c` without intermediate overflow, rounding up if `round_up` is set.
+/// Returns `None` if the result does not fit into a u128
+pub(crate) fn mul_div(a: u128, b: u128, c: u128, round_up: bool) -> Option<u128> {
+    let product = U256::from(a) * U256::from(b);
+    let c = U256::from(c);
+    let mut result = product / c;
+    if round_up && !(product % c).is_zero() {
+        result = result + U256::one();
+    }
+    if result > U256::from(u128::MAX) {
+        None
+    } else {
+        Some(result.as_u128())
+    }
+}
+
+/// Reduce the fraction `numerator / denominator` by the greatest common divisor
+pub(crate) fn reduce_fraction(numerator: U256, denominator: U256) -> (U256, U256) {
+    let (mut a, mut b) = (numerator, denominator);
+    while !b.is_zero() {
+        let rem = a % b;
+        a = b;
+        b = rem;
+    }
+    if a.is_zero() {
+        return (numerator, denominator);
+    }
+    (numerator / a, denominator / a)
+}
+
+/// Approximate the fraction `numerator / denominator` by one whose terms fit into a u128,
+/// rounding the value down
+pub(crate) fn fit_fraction(numerator: U256, denominator: U256) -> (u128, u128) {
+    let bits = numerator.bits().max(denominator.bits());
+    if bits <= 128 {
+        return (numerator.as_u128(), denominator.as_u128());
+    }
+    // Leave one bit of headroom so that rounding the denominator up cannot overflow
+    let shift = bits - 127;
+    let numerator = numerator >> shift;
+    let denominator = ((denominator - U256::one()) >> shift) + U256::one();
+    (numerator.as_u128(), denominator.as_u128())
+}
+
+/// Check that a number of seconds someone waits for is at most `MAX_DELAY`
+pub(crate) fn assert_valid_delay(name: &str, seconds: u64) {
+    if seconds > MAX_DELAY {
+        panic!("Expected the {} to be at most {} seconds, got {}", name, MAX_DELAY, seconds);
+    }
+}
+
+/// Check that a number of seconds holders get to react to a change is between `MIN_DELAY` and
+/// `MAX_DELAY`
+pub(crate) fn assert_valid_notice(name: &str, seconds: u64) {
+    if seconds < MIN_DELAY {
+        panic!("Expected the {} to be at least {} seconds, got {}", name, MIN_DELAY, seconds);
+    }
+    assert_valid_delay(name, seconds);
+}
+
+/// The block timestamp, in nanoseconds, `seconds` from now
+pub(crate) fn timestamp_after(seconds: u64) -> u64 {
+    seconds
+        .checked_mul(NANOS_PER_SECOND)
+        .and_then(|nanos| env::block_timestamp().checked_add(nanos))
+        .unwrap_or_else(|| panic!("The timestamp {} seconds from now overflows", seconds))
+}
@@ -0,0 +1,125 @@
+use near_internal_balances_plugin::SudoInternalBalanceFungibleToken;
+use near_sdk::json_types::U128;
+use near_sdk::{env, log, near_bindgen, serde_json, AccountId, Balance, Promise, PromiseResult};
+
+use crate::external::{
+    ext_ft, ext_self, GAS_FOR_FT_TRANSFER, GAS_FOR_FT_TRANSFER_CALL, GAS_FOR_RESOLVE_TRANSFER,
+};
+use crate::native::is_native_near;
+use crate::{Contract, ContractContract};
+
+#[near_bindgen]
+impl Contract {
+    /// Put back whatever part of an underlying transfer from `unwrap_and_withdraw` failed or
+    /// was refunded into the internal balance of `account_id`
+    ///
+    /// return the amount which was actually transferred
+    #[private]
+    pub fn resolve_unwrap_withdraw(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        is_call: bool,
+    ) -> U128 {
+        let amount: Balance = amount.into();
+        let refund = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => {
+                if is_call {
+                    // `ft_transfer_call` returns the amount used by the receiver
+                    let used =
+                        serde_json::from_slice::<U128>(&value).map(|u| u.0).unwrap_or(amount);
+                    amount - std::cmp::min(used, amount)
+                } else {
+                    0
+                }
+            }
+            PromiseResult::Failed => amount,
+        };
+        if refund > 0 {
+            log!("Returning {} of {} to the internal balance of @{}", refund, token_id, account_id);
+            self.increase_balance(&account_id, &token_id, refund);
+        }
+        U128(amount - refund)
+    }
+}
+
+impl Contract {
+    /// Burn `amount` set tokens from `account_id` and transfer the underlying tokens to
+    /// `receiver_id`, using `ft_transfer_call` if a `msg` is given. Native NEAR is always sent
+    /// with a plain transfer.
+    /// Each transfer is resolved separately so that a failure only returns the affected token to
+    /// the internal balance of `account_id`. The attached deposit has to cover one yoctoNEAR for
+    /// every token transfer
+    pub(crate) fn unwrap_and_withdraw_internal(
+        &mut self,
+        account_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+        msg: Option<String>,
+    ) -> Promise {
+        self.assert_internal_account_registered(account_id);
+
+        let redeemed: Vec<(AccountId, Balance)> = self
+            .redeem(account_id, amount)
+            .into_iter()
+            .filter(|(_, amount_underlying)| *amount_underlying > 0)
+            .collect();
+        // Every `ft_transfer` and `ft_transfer_call` needs one yoctoNEAR attached
+        let ft_transfers =
+            redeemed.iter().filter(|(token_id, _)| !is_native_near(token_id)).count() as Balance;
+        let required_deposit = std::cmp::max(ft_transfers, 1);
+        if env::attached_deposit() < required_deposit {
+            panic!(
+                "Expected at least {} yoctoNEAR attached, one for each transfer",
+                required_deposit
+            );
+        }
+
+        let mut promise: Option<Promise> = None;
+        for (token_id, amount_underlying) in redeemed {
+            if is_native_near(&token_id) {
+                let transfer = self.transfer_near(account_id, receiver_id, amount_underlying);
+                promise = Some(match promise {
+                    Some(promise) => promise.and(transfer),
+                    None => transfer,
+                });
+                continue;
+            }
+            let transfer = match &msg {
+                Some(msg) => ext_ft::ft_transfer_call(
+                    receiver_id.clone(),
+                    amount_underlying.into(),
+                    None,
+                    msg.clone(),
+                    &token_id,
+                    1,
+                    GAS_FOR_FT_TRANSFER_CALL,
+                ),
+                None => ext_ft::ft_transfer(
+                    receiver_id.clone(),
+                    amount_underlying.into(),
+                    None,
+                    &token_id,
+                    1,
+                    GAS_FOR_FT_TRANSFER,
+                ),
+            }
+            .then(ext_self::resolve_unwrap_withdraw(
+                account_id.clone(),
+                token_id.clone(),
+                amount_underlying.into(),
+                msg.is_some(),
+                &env::current_account_id(),
+                0,
+                GAS_FOR_RESOLVE_TRANSFER,
+            ));
+            promise = Some(match promise {
+                Some(promise) => promise.and(transfer),
+                None => transfer,
+            });
+        }
+        promise.expect("Expected a non-zero amount of underlying tokens to withdraw")
+    }
+}